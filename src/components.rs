@@ -1,16 +1,161 @@
+use std::fmt;
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct CharacterAttributes {
   pub max_hp: i32,
   pub hp: i32,
-  pub defense: i32,
-  pub power: i32,
+  pub defense: i32, // Taken off the damage of every hit
+  pub power: i32, // Added to the damage of every hit
+  pub damage: Dice,
+  pub evasion: i32, // Taken off the attacker's chance to hit, in percent
+  pub level: i32,
+  pub xp: i32, // For monsters this is the XP granted to whoever kills them
+  pub speed: i32, // Energy gained every tick of game time
+  pub energy: i32, // Spent by actions. Acting needs a full action's worth.
+  pub on_hit: Option<StatusEffect> // Given to whoever its attacks damage
+}
+
+pub const MAX_DICE: i32 = 100; // For both the number of dice and their sides
+
+/* `count` dice with `sides` sides each, written like 2d6 */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Dice {
+  pub count: i32,
+  pub sides: i32
+}
+
+impl Dice {
+  /* Rejects counts or sides outside 1 to `MAX_DICE` */
+  pub fn new(count: i32, sides: i32) -> Option<Dice> {
+    let in_range = |n: i32| n >= 1 && n <= MAX_DICE;
+    if in_range(count) && in_range(sides) {
+      Some(Dice { count: count, sides: sides })
+    } else {
+      None
+    }
+  }
+
+  pub fn parse(text: &str) -> Option<Dice> {
+    let mut parts = text.splitn(2, 'd');
+    let count = parts.next().and_then(|count| count.parse().ok());
+    let sides = parts.next().and_then(|sides| sides.parse().ok());
+    match (count, sides) {
+      (Some(count), Some(sides)) => Dice::new(count, sides),
+      _ => None
+    }
+  }
+}
+
+impl fmt::Display for Dice {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}d{}", self.count, self.sides)
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AiMode {
+  Idle, // Waits where it is until it sees the player
+  Wander, // Roams at random until it sees the player
+  Hunt, // Chases the player, or searches where they were last seen
+  Flee // Runs from the player while badly hurt
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Ai {
+  pub sight_radius: i32, // How far away it can spot the player, given a clear line of sight
+  pub mode: AiMode,
+  pub last_seen_player: Option<(i32, i32)>,
+  pub chase_turns_left: i32 // How much longer a hunt goes on without seeing the player
+}
+
+impl Ai {
+  pub fn new(sight_radius: i32) -> Self {
+    Ai {
+      sight_radius: sight_radius,
+      mode: AiMode::Idle,
+      last_seen_player: None,
+      chase_turns_left: 0
+    }
+  }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
-pub struct Ai;
+pub enum StatusKind {
+  Poison, // Loses `magnitude` HP every turn
+  Regeneration, // Heals `magnitude` HP every turn
+  Confusion, // Stumbles around at random
+  Stun, // Loses every turn
+  Haste // Gains `magnitude` extra speed
+}
+
+impl StatusKind {
+  /* Whether a second dose adds its duration to what's left of the first. Otherwise it only
+   * restarts the count. Either way the stronger magnitude is kept.
+   */
+  pub fn stacks(&self) -> bool {
+    match *self {
+      StatusKind::Poison => true,
+      _ => false
+    }
+  }
 
+  /* Completes "<name> is ..." */
+  pub fn adjective(&self) -> &'static str {
+    match *self {
+      StatusKind::Poison => "poisoned",
+      StatusKind::Regeneration => "regenerating",
+      StatusKind::Confusion => "confused",
+      StatusKind::Stun => "stunned",
+      StatusKind::Haste => "hasted"
+    }
+  }
+}
+
+/* A timed effect, ticked at the start of each of its owner's turns */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StatusEffect {
+  pub kind: StatusKind,
+  pub turns_left: i32,
+  pub magnitude: i32
+}
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Item {
-  Heal
+  Heal,
+  Lightning,
+  Fireball,
+  Confuse,
+  Teleport,
+  Regenerate,
+  Haste,
+  Poison
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Slot {
+  MainHand,
+  OffHand,
+  Head,
+  Body
+}
+
+impl fmt::Display for Slot {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let name = match *self {
+      Slot::MainHand => "main hand",
+      Slot::OffHand => "off hand",
+      Slot::Head => "head",
+      Slot::Body => "body"
+    };
+    write!(f, "{}", name)
+  }
+}
+
+/* Gear that can be worn in a slot. The bonuses only count while it's equipped. */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Equipment {
+  pub slot: Slot,
+  pub equipped: bool,
+  pub power_bonus: i32,
+  pub defense_bonus: i32
 }
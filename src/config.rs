@@ -0,0 +1,290 @@
+/* A reader for the small subset of TOML our data and config files use:
+ *
+ *   # comments
+ *   key = "string" | 123 | -4.5 | true | [1, 2, "three"]
+ *   [table]
+ *   [[array_of_tables]]
+ *
+ * Every table keeps the line numbers of its header and entries so callers can point at the
+ * offending line when a value doesn't validate. Files written against this subset are valid
+ * TOML, so they can be moved to a full TOML parser later without changes.
+ */
+
+use std::fs::File;
+use std::io::Read;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+  Str(String),
+  Int(i64),
+  Float(f64),
+  Bool(bool),
+  Array(Vec<Value>)
+}
+
+impl Value {
+  pub fn type_name(&self) -> &'static str {
+    match *self {
+      Value::Str(_) => "a string",
+      Value::Int(_) => "an integer",
+      Value::Float(_) => "a number",
+      Value::Bool(_) => "a boolean",
+      Value::Array(_) => "an array",
+    }
+  }
+}
+
+#[derive(Debug)]
+pub struct Entry {
+  pub key: String,
+  pub value: Value,
+  pub line: usize
+}
+
+#[derive(Debug)]
+pub struct Table {
+  pub name: String, // Empty for the entries before the first header
+  pub is_array_entry: bool, // Declared with [[name]]
+  pub line: usize,
+  pub entries: Vec<Entry>
+}
+
+impl Table {
+  pub fn get(&self, key: &str) -> Option<&Entry> {
+    self.entries.iter().find(|entry| entry.key == key)
+  }
+}
+
+pub fn load(path: &str) -> Result<Vec<Table>, String> {
+  let text = read(path)?;
+  parse(&text).map_err(|err| format!("{}: {}", path, err))
+}
+
+pub fn read(path: &str) -> Result<String, String> {
+  let mut text = String::new();
+  File::open(path)
+    .and_then(|mut file| file.read_to_string(&mut text))
+    .map_err(|err| format!("unable to read {}: {}", path, err))?;
+  Ok(text)
+}
+
+/* Returns the tables in file order. The first table is always the unnamed root table. */
+pub fn parse(text: &str) -> Result<Vec<Table>, String> {
+  let mut tables = vec![Table { name: String::new(), is_array_entry: false, line: 0, entries: vec![] }];
+
+  for (idx, raw_line) in text.lines().enumerate() {
+    let line_num = idx + 1;
+    let line = strip_comment(raw_line).trim();
+    if line.is_empty() {
+      continue;
+    }
+
+    if line.starts_with('[') {
+      let (name, is_array_entry) = if line.starts_with("[[") && line.ends_with("]]") {
+        (&line[2..line.len() - 2], true)
+      } else if line.ends_with(']') {
+        (&line[1..line.len() - 1], false)
+      } else {
+        return Err(format!("line {}: malformed table header", line_num));
+      };
+      let name = name.trim();
+      if name.is_empty() || !name.chars().all(is_bare_key_char) {
+        return Err(format!("line {}: invalid table name '{}'", line_num, name));
+      }
+      if !is_array_entry && tables.iter().any(|table| table.name == name) {
+        return Err(format!("line {}: table [{}] is defined twice", line_num, name));
+      }
+      tables.push(Table { name: name.into(), is_array_entry: is_array_entry, line: line_num, entries: vec![] });
+      continue;
+    }
+
+    let equals = line.find('=').ok_or_else(|| format!("line {}: expected 'key = value'", line_num))?;
+    let key = line[..equals].trim();
+    if key.is_empty() || !key.chars().all(is_bare_key_char) {
+      return Err(format!("line {}: invalid key '{}'", line_num, key));
+    }
+
+    let mut parser = ValueParser { text: line[equals + 1..].trim(), pos: 0 };
+    let value = parser.value().map_err(|err| format!("line {}: {}", line_num, err))?;
+    if !parser.rest().trim().is_empty() {
+      return Err(format!("line {}: unexpected '{}' after the value", line_num, parser.rest().trim()));
+    }
+
+    let table = tables.last_mut().unwrap();
+    if table.get(key).is_some() {
+      return Err(format!("line {}: '{}' is set twice", line_num, key));
+    }
+    table.entries.push(Entry { key: key.into(), value: value, line: line_num });
+  }
+
+  Ok(tables)
+}
+
+fn is_bare_key_char(c: char) -> bool {
+  c.is_ascii_alphanumeric() || c == '_' || c == '-'
+}
+
+/* Drops everything after a '#' that isn't inside a string */
+fn strip_comment(line: &str) -> &str {
+  let mut in_string = false;
+  let mut escaped = false;
+  for (idx, c) in line.char_indices() {
+    match c {
+      _ if escaped => escaped = false,
+      '\\' if in_string => escaped = true,
+      '"' => in_string = !in_string,
+      '#' if !in_string => return &line[..idx],
+      _ => {}
+    }
+  }
+  line
+}
+
+struct ValueParser<'a> {
+  text: &'a str,
+  pos: usize
+}
+
+impl<'a> ValueParser<'a> {
+  fn rest(&self) -> &'a str {
+    &self.text[self.pos..]
+  }
+
+  fn skip_whitespace(&mut self) {
+    let rest = self.rest();
+    self.pos += rest.find(|c: char| !c.is_whitespace()).unwrap_or(rest.len());
+  }
+
+  fn value(&mut self) -> Result<Value, String> {
+    self.skip_whitespace();
+    match self.rest().chars().next() {
+      Some('"') => self.string(),
+      Some('[') => self.array(),
+      Some(_) => self.scalar(),
+      None => Err("missing value".into())
+    }
+  }
+
+  fn string(&mut self) -> Result<Value, String> {
+    self.pos += 1; // Opening quote
+    let mut out = String::new();
+    let mut chars = self.rest().char_indices();
+    while let Some((idx, c)) = chars.next() {
+      match c {
+        '"' => {
+          self.pos += idx + 1;
+          return Ok(Value::Str(out));
+        }
+        '\\' => {
+          let escaped = match chars.next() {
+            Some((_, 'n')) => '\n',
+            Some((_, 't')) => '\t',
+            Some((_, '"')) => '"',
+            Some((_, '\\')) => '\\',
+            Some((_, other)) => return Err(format!("unsupported escape '\\{}'", other)),
+            None => break
+          };
+          out.push(escaped);
+        }
+        _ => out.push(c)
+      }
+    }
+    Err("unterminated string".into())
+  }
+
+  fn array(&mut self) -> Result<Value, String> {
+    self.pos += 1; // Opening bracket
+    let mut values = vec![];
+    loop {
+      self.skip_whitespace();
+      if self.rest().starts_with(']') {
+        self.pos += 1;
+        return Ok(Value::Array(values));
+      }
+      values.push(self.value()?);
+      self.skip_whitespace();
+      if self.rest().starts_with(',') {
+        self.pos += 1;
+      } else if !self.rest().starts_with(']') {
+        return Err("expected ',' or ']' in array".into());
+      }
+    }
+  }
+
+  fn scalar(&mut self) -> Result<Value, String> {
+    let rest = self.rest();
+    let len = rest.find(|c: char| c == ',' || c == ']' || c.is_whitespace()).unwrap_or(rest.len());
+    let token = &rest[..len];
+    self.pos += len;
+
+    let digits = token.replace('_', "");
+    if token == "true" {
+      Ok(Value::Bool(true))
+    } else if token == "false" {
+      Ok(Value::Bool(false))
+    } else if let Ok(int) = digits.parse::<i64>() {
+      Ok(Value::Int(int))
+    } else if let Ok(float) = digits.parse::<f64>() {
+      Ok(Value::Float(float))
+    } else {
+      Err(format!("invalid value '{}' (strings need double quotes)", token))
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn root_value(text: &str, key: &str) -> Value {
+    let tables = parse(text).unwrap();
+    tables[0].get(key).unwrap().value.clone()
+  }
+
+  #[test]
+  fn strings_keep_hashes_and_escapes() {
+    assert_eq!(root_value("key = \"a # b\" # comment", "key"), Value::Str("a # b".into()));
+    assert_eq!(root_value(r#"key = "say \"hi\"\n\t\\""#, "key"), Value::Str("say \"hi\"\n\t\\".into()));
+    assert_eq!(root_value(r##"key = "\"#\"" # "comment""##, "key"), Value::Str("\"#\"".into()));
+    assert!(parse(r#"key = "\q""#).unwrap_err().contains("unsupported escape"));
+    assert!(parse("key = \"open").unwrap_err().contains("unterminated string"));
+  }
+
+  #[test]
+  fn arrays() {
+    assert_eq!(root_value("key = []", "key"), Value::Array(vec![]));
+    assert_eq!(root_value("key = [1, -2.5, \"three\", true]", "key"),
+               Value::Array(vec![Value::Int(1), Value::Float(-2.5), Value::Str("three".into()), Value::Bool(true)]));
+    assert_eq!(root_value("key = [[1, 40], [4, 10],]", "key"),
+               Value::Array(vec![Value::Array(vec![Value::Int(1), Value::Int(40)]),
+                                 Value::Array(vec![Value::Int(4), Value::Int(10)])]));
+  }
+
+  #[test]
+  fn missing_closing_bracket() {
+    assert!(parse("key = [1, 2").unwrap_err().contains("expected ',' or ']' in array"));
+    assert!(parse("key = [1,").unwrap_err().contains("missing value"));
+    assert!(parse("[table").unwrap_err().contains("malformed table header"));
+    assert!(parse("[[entry]").unwrap_err().contains("invalid table name"));
+  }
+
+  #[test]
+  fn duplicate_keys_and_tables() {
+    assert!(parse("key = 1\nkey = 2").unwrap_err().contains("'key' is set twice"));
+    assert!(parse("[a]\n[a]").unwrap_err().contains("table [a] is defined twice"));
+    // The same key in two entries of an array of tables is fine
+    let tables = parse("[[monster]]\nname = \"Orc\"\n[[monster]]\nname = \"Troll\"").unwrap();
+    assert_eq!(tables.len(), 3);
+    assert_eq!(tables[2].get("name").unwrap().value, Value::Str("Troll".into()));
+  }
+
+  #[test]
+  fn errors_and_entries_report_their_lines() {
+    let tables = parse("# header\n\n[[monster]]\nname = \"Orc\"\n").unwrap();
+    assert_eq!(tables[1].line, 3);
+    assert_eq!(tables[1].get("name").unwrap().line, 4);
+    assert!(parse("a = 1\n\n# comment\nb = 2\nb = 3").unwrap_err().starts_with("line 5:"));
+    assert!(parse("a = 1\nb = oops").unwrap_err().starts_with("line 2: invalid value 'oops'"));
+    assert!(parse("a = 1\njust some text").unwrap_err().starts_with("line 2: expected 'key = value'"));
+  }
+}
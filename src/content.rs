@@ -0,0 +1,407 @@
+/* Monster and item templates, loaded from `data/content.toml` at startup so they can be tuned
+ * without recompiling. See that file for the list of fields.
+ */
+
+use tcod::colors::Color;
+
+use components;
+use config::{self, Entry, Table, Value};
+use save;
+
+pub const CONTENT_PATH: &'static str = "data/content.toml";
+
+// Keeps the total of every template's weight well within a u32
+const MAX_SPAWN_WEIGHT: u32 = 10000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AiKind {
+  Basic
+}
+
+#[derive(Clone, Debug)]
+pub struct MonsterTemplate {
+  pub name: String,
+  pub glyph: char,
+  pub color: Color,
+  pub attributes: components::CharacterAttributes,
+  pub spawn_weights: Vec<(i32, u32)>, // (depth, weight) pairs, see `value_at_depth`
+  pub min_depth: i32,
+  pub sight_radius: i32,
+  pub ai: AiKind
+}
+
+#[derive(Clone, Debug)]
+pub struct ItemTemplate {
+  pub name: String,
+  pub glyph: char,
+  pub color: Color,
+  pub spawn_weights: Vec<(i32, u32)>, // (depth, weight) pairs, see `value_at_depth`
+  pub min_depth: i32,
+  // Exactly one of these is set
+  pub effect: Option<components::Item>,
+  pub equipment: Option<components::Equipment>
+}
+
+impl MonsterTemplate {
+  pub fn spawn_weight(&self, depth: i32) -> u32 {
+    if depth < self.min_depth { 0 } else { value_at_depth(&self.spawn_weights, depth).unwrap_or(0) }
+  }
+}
+
+impl ItemTemplate {
+  pub fn spawn_weight(&self, depth: i32) -> u32 {
+    if depth < self.min_depth { 0 } else { value_at_depth(&self.spawn_weights, depth).unwrap_or(0) }
+  }
+}
+
+/* Looks up a value that changes with dungeon depth. `table` holds (depth, value) pairs sorted
+ * by depth, each value holding from its depth until the next pair's. None above the first one.
+ */
+pub fn value_at_depth<T: Copy>(table: &[(i32, T)], depth: i32) -> Option<T> {
+  table.iter().take_while(|&&(from_depth, _)| from_depth <= depth).last().map(|&(_, value)| value)
+}
+
+pub struct Content {
+  pub monsters: Vec<MonsterTemplate>,
+  pub items: Vec<ItemTemplate>,
+  pub checksum: u64 // Of the file's text, so recordings can tell when it has changed
+}
+
+impl Content {
+  pub fn load(path: &str) -> Result<Self, String> {
+    let text = config::read(path)?;
+    let mut content = config::parse(&text)
+      .and_then(|tables| Content::from_tables(&tables))
+      .map_err(|err| format!("{}: {}", path, err))?;
+    content.checksum = save::checksum(&text);
+    Ok(content)
+  }
+
+  fn from_tables(tables: &[Table]) -> Result<Self, String> {
+    let mut content = Content { monsters: vec![], items: vec![], checksum: 0 };
+
+    for table in tables {
+      match (table.name.as_ref(), table.is_array_entry) {
+        ("", _) if table.entries.is_empty() => {}
+        ("monster", true) => content.monsters.push(monster_from_table(table)?),
+        ("item", true) => content.items.push(item_from_table(table)?),
+        ("", _) => {
+          return Err(format!("line {}: '{}' must be inside a [[monster]] or [[item]] entry",
+                             table.entries[0].line, table.entries[0].key));
+        }
+        (name, _) => {
+          return Err(format!("line {}: unknown section '{}', expected [[monster]] or [[item]]",
+                             table.line, name));
+        }
+      }
+    }
+
+    if content.monsters.is_empty() {
+      return Err("no [[monster]] entries".into());
+    }
+    for (idx, monster) in content.monsters.iter().enumerate() {
+      if content.monsters[..idx].iter().any(|other| other.name == monster.name) {
+        return Err(format!("monster '{}' is defined more than once", monster.name));
+      }
+    }
+    for (idx, item) in content.items.iter().enumerate() {
+      if content.items[..idx].iter().any(|other| other.name == item.name) {
+        return Err(format!("item '{}' is defined more than once", item.name));
+      }
+    }
+
+    Ok(content)
+  }
+}
+
+const ITEM_EFFECTS: &'static [(&'static str, components::Item)] = &[
+  ("heal", components::Item::Heal),
+  ("lightning", components::Item::Lightning),
+  ("fireball", components::Item::Fireball),
+  ("confuse", components::Item::Confuse),
+  ("teleport", components::Item::Teleport),
+  ("regenerate", components::Item::Regenerate),
+  ("haste", components::Item::Haste),
+  ("poison", components::Item::Poison),
+];
+
+const STATUSES: &'static [(&'static str, components::StatusKind)] = &[
+  ("poison", components::StatusKind::Poison),
+  ("regeneration", components::StatusKind::Regeneration),
+  ("confusion", components::StatusKind::Confusion),
+  ("stun", components::StatusKind::Stun),
+  ("haste", components::StatusKind::Haste),
+];
+
+const SLOTS: &'static [(&'static str, components::Slot)] = &[
+  ("main_hand", components::Slot::MainHand),
+  ("off_hand", components::Slot::OffHand),
+  ("head", components::Slot::Head),
+  ("body", components::Slot::Body),
+];
+
+const MONSTER_FIELDS: &'static [&'static str] = &[
+  "name", "glyph", "color", "max_hp", "hp", "defense", "power", "damage", "evasion", "xp", "speed",
+  "spawn_weight", "min_depth", "sight_radius", "ai", "on_hit", "on_hit_turns", "on_hit_magnitude"
+];
+
+const ITEM_FIELDS: &'static [&'static str] = &[
+  "name", "glyph", "color", "spawn_weight", "min_depth", "effect", "slot", "power_bonus",
+  "defense_bonus"
+];
+
+fn monster_from_table(table: &Table) -> Result<MonsterTemplate, String> {
+  let entry = TemplateEntry::new("monster", table, MONSTER_FIELDS)?;
+
+  let max_hp = entry.int("max_hp", 1, i32::max_value())?;
+  let hp = match table.get("hp") {
+    Some(_) => entry.int("hp", 1, max_hp)?,
+    None => max_hp
+  };
+  let on_hit = match table.get("on_hit") {
+    Some(_) => Some(components::StatusEffect {
+      kind: entry.one_of("on_hit", STATUSES)?,
+      turns_left: entry.int("on_hit_turns", 1, i32::max_value())?,
+      magnitude: entry.optional_int("on_hit_magnitude", 0, 100, 0)?
+    }),
+    None => {
+      if let Some(field) = table.get("on_hit_turns").or(table.get("on_hit_magnitude")) {
+        return Err(entry.error(Some(field), &format!("{} only applies with on_hit", field.key)));
+      }
+      None
+    }
+  };
+  let ai = match entry.string("ai")?.as_ref() {
+    "basic" => AiKind::Basic,
+    other => {
+      let message = format!("unknown ai '{}' (expected \"basic\")", other);
+      return Err(entry.error(table.get("ai"), &message));
+    }
+  };
+
+  Ok(MonsterTemplate {
+    glyph: entry.glyph()?,
+    color: entry.color()?,
+    attributes: components::CharacterAttributes {
+      max_hp: max_hp,
+      hp: hp,
+      defense: entry.int("defense", 0, i32::max_value())?,
+      power: entry.int("power", 0, i32::max_value())?,
+      damage: entry.dice("damage")?,
+      evasion: entry.int("evasion", 0, 100)?,
+      level: 1,
+      xp: entry.int("xp", 0, i32::max_value())?,
+      speed: entry.int("speed", 1, i32::max_value())?,
+      energy: 0,
+      on_hit: on_hit
+    },
+    spawn_weights: entry.depth_table("spawn_weight", MAX_SPAWN_WEIGHT)?,
+    min_depth: entry.int("min_depth", 1, i32::max_value())?,
+    sight_radius: entry.int("sight_radius", 0, i32::max_value())?,
+    ai: ai,
+    name: entry.name
+  })
+}
+
+fn item_from_table(table: &Table) -> Result<ItemTemplate, String> {
+  let entry = TemplateEntry::new("item", table, ITEM_FIELDS)?;
+
+  let effect = match table.get("effect") {
+    Some(_) => Some(entry.one_of("effect", ITEM_EFFECTS)?),
+    None => None
+  };
+
+  let equipment = match table.get("slot") {
+    Some(_) => Some(components::Equipment {
+      slot: entry.one_of("slot", SLOTS)?,
+      equipped: false,
+      power_bonus: entry.optional_int("power_bonus", -100, 100, 0)?,
+      defense_bonus: entry.optional_int("defense_bonus", -100, 100, 0)?
+    }),
+    None => {
+      if let Some(bonus) = table.get("power_bonus").or(table.get("defense_bonus")) {
+        return Err(entry.error(Some(bonus), &format!("{} only applies to items with a slot", bonus.key)));
+      }
+      None
+    }
+  };
+
+  match (effect.is_some(), equipment.is_some()) {
+    (false, false) => return Err(entry.error(None, "needs either an effect or a slot")),
+    (true, true) => return Err(entry.error(table.get("slot"), "can't have both an effect and a slot")),
+    _ => {}
+  }
+
+  Ok(ItemTemplate {
+    glyph: entry.glyph()?,
+    color: entry.color()?,
+    spawn_weights: entry.depth_table("spawn_weight", MAX_SPAWN_WEIGHT)?,
+    min_depth: entry.int("min_depth", 1, i32::max_value())?,
+    effect: effect,
+    equipment: equipment,
+    name: entry.name
+  })
+}
+
+/* Field accessors for a single [[monster]] or [[item]] table that produce errors naming the
+ * entry and line at fault.
+ */
+struct TemplateEntry<'a> {
+  kind: &'static str,
+  name: String,
+  table: &'a Table
+}
+
+impl<'a> TemplateEntry<'a> {
+  fn new(kind: &'static str, table: &'a Table, known_fields: &[&str]) -> Result<Self, String> {
+    let mut entry = TemplateEntry { kind: kind, name: String::new(), table: table };
+    entry.name = entry.string("name")?;
+    if entry.name.is_empty() {
+      return Err(entry.error(table.get("name"), "name can't be empty"));
+    }
+    if let Some(unknown) = table.entries.iter().find(|e| !known_fields.contains(&e.key.as_ref())) {
+      return Err(entry.error(Some(unknown), &format!("unknown field '{}'", unknown.key)));
+    }
+    Ok(entry)
+  }
+
+  fn error(&self, field: Option<&Entry>, message: &str) -> String {
+    let line = field.map_or(self.table.line, |field| field.line);
+    if self.name.is_empty() {
+      format!("line {}: {} entry: {}", line, self.kind, message)
+    } else {
+      format!("line {}: {} '{}': {}", line, self.kind, self.name, message)
+    }
+  }
+
+  fn field(&self, key: &str) -> Result<&'a Entry, String> {
+    self.table.get(key).ok_or_else(|| self.error(None, &format!("missing field '{}'", key)))
+  }
+
+  fn string(&self, key: &str) -> Result<String, String> {
+    let field = self.field(key)?;
+    match field.value {
+      Value::Str(ref s) => Ok(s.clone()),
+      ref other => {
+        let message = format!("{} must be a string, found {}", key, other.type_name());
+        Err(self.error(Some(field), &message))
+      }
+    }
+  }
+
+  fn int(&self, key: &str, min: i32, max: i32) -> Result<i32, String> {
+    let field = self.field(key)?;
+    match field.value {
+      Value::Int(n) if n >= min as i64 && n <= max as i64 => Ok(n as i32),
+      Value::Int(n) if max == i32::max_value() => {
+        Err(self.error(Some(field), &format!("{} must be at least {}, found {}", key, min, n)))
+      }
+      Value::Int(n) => {
+        let message = format!("{} must be between {} and {}, found {}", key, min, max, n);
+        Err(self.error(Some(field), &message))
+      }
+      ref other => {
+        let message = format!("{} must be an integer, found {}", key, other.type_name());
+        Err(self.error(Some(field), &message))
+      }
+    }
+  }
+
+  fn optional_int(&self, key: &str, min: i32, max: i32, default: i32) -> Result<i32, String> {
+    match self.table.get(key) {
+      Some(_) => self.int(key, min, max),
+      None => Ok(default)
+    }
+  }
+
+  /* A string field that has to be one of the names in `choices` */
+  fn one_of<T: Copy>(&self, key: &str, choices: &[(&str, T)]) -> Result<T, String> {
+    let name = self.string(key)?;
+    match choices.iter().find(|&&(choice, _)| choice == name) {
+      Some(&(_, value)) => Ok(value),
+      None => {
+        let names: Vec<_> = choices.iter().map(|&(choice, _)| format!("\"{}\"", choice)).collect();
+        let message = format!("unknown {} '{}' (expected one of {})", key, name, names.join(", "));
+        Err(self.error(self.table.get(key), &message))
+      }
+    }
+  }
+
+  fn dice(&self, key: &str) -> Result<components::Dice, String> {
+    let text = self.string(key)?;
+    components::Dice::parse(&text).ok_or_else(|| {
+      let message = format!("{} must be dice like \"1d6\", with 1 to {} dice of 1 to {} sides, found \"{}\"",
+                            key, components::MAX_DICE, components::MAX_DICE, text);
+      self.error(self.table.get(key), &message)
+    })
+  }
+
+  /* Either a single number for every depth, or [depth, number] pairs in increasing order of
+   * depth for numbers that change as the player goes deeper. Numbers go from 0 to `max`.
+   */
+  fn depth_table(&self, key: &str, max: u32) -> Result<Vec<(i32, u32)>, String> {
+    let field = self.field(key)?;
+    let in_range = |n: i64, min: i64, max: i64| n >= min && n <= max;
+    let format_error = || {
+      let message = format!("{} must be a number from 0 to {}, or [depth, number] pairs of them with depths from 1 up",
+                            key, max);
+      self.error(Some(field), &message)
+    };
+
+    let max_depth = i32::max_value() as i64;
+    let pairs = match field.value {
+      Value::Int(n) if in_range(n, 0, max as i64) => return Ok(vec![(1, n as u32)]),
+      Value::Array(ref pairs) if !pairs.is_empty() => pairs,
+      _ => return Err(format_error())
+    };
+    let mut table: Vec<(i32, u32)> = vec![];
+    for pair in pairs {
+      let (depth, value) = match *pair {
+        Value::Array(ref pair) if pair.len() == 2 => match (&pair[0], &pair[1]) {
+          (&Value::Int(depth), &Value::Int(value)) if in_range(depth, 1, max_depth) && in_range(value, 0, max as i64) => {
+            (depth as i32, value as u32)
+          }
+          _ => return Err(format_error())
+        },
+        _ => return Err(format_error())
+      };
+      if let Some(&(prev_depth, _)) = table.last() {
+        if depth <= prev_depth {
+          let message = format!("{} depths must increase, found {} after {}", key, depth, prev_depth);
+          return Err(self.error(Some(field), &message));
+        }
+      }
+      table.push((depth, value));
+    }
+    Ok(table)
+  }
+
+  fn glyph(&self) -> Result<char, String> {
+    let glyph = self.string("glyph")?;
+    let mut chars = glyph.chars();
+    match (chars.next(), chars.next()) {
+      (Some(c), None) => Ok(c),
+      _ => {
+        let message = format!("glyph must be a single character, found \"{}\"", glyph);
+        Err(self.error(self.table.get("glyph"), &message))
+      }
+    }
+  }
+
+  fn color(&self) -> Result<Color, String> {
+    let field = self.field("color")?;
+    let rgb = match field.value {
+      Value::Array(ref values) if values.len() == 3 => {
+        values.iter().map(|value| match *value {
+          Value::Int(n) if n >= 0 && n <= 255 => Some(n as u8),
+          _ => None
+        }).collect::<Option<Vec<u8>>>()
+      }
+      _ => None
+    };
+    match rgb {
+      Some(rgb) => Ok(Color { r: rgb[0], g: rgb[1], b: rgb[2] }),
+      None => Err(self.error(Some(field), "color must be [red, green, blue] with values from 0 to 255"))
+    }
+  }
+}
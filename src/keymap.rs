@@ -0,0 +1,241 @@
+/* What the player can do, and which keys do it.
+ *
+ * Bindings are read from `data/keymap.toml` at startup. Each entry names an action and the
+ * keys for it, written the same way as in a key script (see `keys::parse_key`):
+ *
+ *   move_up = ["up", "numpad8", "k"]
+ *   explore = "x"
+ *
+ * Actions the file leaves out keep their default keys, and a missing file means every action
+ * does. Menus and targeting still read their own keys (letters, Enter and Escape).
+ *
+ * Key scripts are always read with the default bindings, and a recording stores the bindings it
+ * was made with, so neither depends on the local keymap file.
+ */
+
+use std::path::Path;
+use tcod::input::{Key, KeyCode};
+
+use config::{self, Entry, Value};
+use keys;
+
+pub const KEYMAP_PATH: &'static str = "data/keymap.toml";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+  MoveUp,
+  MoveDown,
+  MoveLeft,
+  MoveRight,
+  MoveUpLeft,
+  MoveUpRight,
+  MoveDownLeft,
+  MoveDownRight,
+  Wait,
+  PickUp,
+  Inventory,
+  Descend,
+  Explore,
+  Save,
+  Help,
+  ToggleFullscreen,
+  Exit
+}
+
+impl Action {
+  /* The step a movement action stands for */
+  pub fn direction(&self) -> Option<(i32, i32)> {
+    match *self {
+      Action::MoveUp => Some((0, -1)),
+      Action::MoveDown => Some((0, 1)),
+      Action::MoveLeft => Some((-1, 0)),
+      Action::MoveRight => Some((1, 0)),
+      Action::MoveUpLeft => Some((-1, -1)),
+      Action::MoveUpRight => Some((1, -1)),
+      Action::MoveDownLeft => Some((-1, 1)),
+      Action::MoveDownRight => Some((1, 1)),
+      _ => None
+    }
+  }
+}
+
+struct ActionInfo {
+  name: &'static str, // As written in the keymap file
+  action: Action,
+  default_keys: &'static [&'static str],
+  description: &'static str // For the help screen
+}
+
+// In the order the help screen lists them
+const ACTIONS: &'static [ActionInfo] = &[
+  ActionInfo { name: "move_up", action: Action::MoveUp,
+               default_keys: &["up", "numpad8", "k"], description: "Move up" },
+  ActionInfo { name: "move_down", action: Action::MoveDown,
+               default_keys: &["down", "numpad2", "j"], description: "Move down" },
+  ActionInfo { name: "move_left", action: Action::MoveLeft,
+               default_keys: &["left", "numpad4", "h"], description: "Move left" },
+  ActionInfo { name: "move_right", action: Action::MoveRight,
+               default_keys: &["right", "numpad6", "l"], description: "Move right" },
+  ActionInfo { name: "move_up_left", action: Action::MoveUpLeft,
+               default_keys: &["home", "numpad7", "y"], description: "Move up and left" },
+  ActionInfo { name: "move_up_right", action: Action::MoveUpRight,
+               default_keys: &["pageup", "numpad9", "u"], description: "Move up and right" },
+  ActionInfo { name: "move_down_left", action: Action::MoveDownLeft,
+               default_keys: &["end", "numpad1", "b"], description: "Move down and left" },
+  ActionInfo { name: "move_down_right", action: Action::MoveDownRight,
+               default_keys: &["pagedown", "numpad3", "n"], description: "Move down and right" },
+  ActionInfo { name: "wait", action: Action::Wait,
+               default_keys: &["numpad5", "."], description: "Wait a turn" },
+  ActionInfo { name: "pick_up", action: Action::PickUp,
+               default_keys: &["g"], description: "Pick up an item" },
+  ActionInfo { name: "inventory", action: Action::Inventory,
+               default_keys: &["i"], description: "Use or equip an item" },
+  ActionInfo { name: "descend", action: Action::Descend,
+               default_keys: &[">"], description: "Take the stairs down" },
+  ActionInfo { name: "explore", action: Action::Explore,
+               default_keys: &["x"], description: "Explore" },
+  ActionInfo { name: "save", action: Action::Save,
+               default_keys: &["s"], description: "Save the game" },
+  ActionInfo { name: "help", action: Action::Help,
+               default_keys: &["?"], description: "Show this screen" },
+  ActionInfo { name: "toggle_fullscreen", action: Action::ToggleFullscreen,
+               default_keys: &["alt+enter"], description: "Toggle fullscreen" },
+  ActionInfo { name: "exit", action: Action::Exit,
+               default_keys: &["escape"], description: "Exit" },
+];
+
+#[derive(Clone)]
+struct Binding {
+  key: Key,
+  key_name: String,
+  action: Action,
+  line: Option<usize> // Where the keymap file set it, None for a default
+}
+
+#[derive(Clone)]
+pub struct Keymap {
+  bindings: Vec<Binding> // In `ACTIONS` order
+}
+
+impl Keymap {
+  pub fn defaults() -> Self {
+    Keymap::from_entries(&[]).expect("the default key bindings conflict")
+  }
+
+  /* Falls back to the defaults when there's no keymap file */
+  pub fn load(path: &str) -> Result<Self, String> {
+    if !Path::new(path).exists() {
+      return Ok(Keymap::defaults());
+    }
+    let tables = config::load(path)?;
+    if let Some(table) = tables.iter().find(|table| !table.name.is_empty()) {
+      return Err(format!("{}: line {}: unexpected section '{}', bindings go at the top level",
+                         path, table.line, table.name));
+    }
+    Keymap::from_entries(&tables[0].entries).map_err(|err| format!("{}: {}", path, err))
+  }
+
+  /* Builds a keymap from (action name, key names, line) triples, as listed by `action_keys` */
+  pub fn from_action_keys(action_keys: &[(String, Vec<String>, usize)]) -> Result<Self, String> {
+    let entries: Vec<Entry> = action_keys.iter().map(|&(ref action, ref key_names, line)| Entry {
+      key: action.clone(),
+      value: Value::Array(key_names.iter().map(|name| Value::Str(name.clone())).collect()),
+      line: line
+    }).collect();
+    Keymap::from_entries(&entries)
+  }
+
+  fn from_entries(entries: &[Entry]) -> Result<Self, String> {
+    if let Some(unknown) = entries.iter().find(|entry| !ACTIONS.iter().any(|info| info.name == entry.key)) {
+      return Err(format!("line {}: unknown action '{}'", unknown.line, unknown.key));
+    }
+
+    let mut keymap = Keymap { bindings: vec![] };
+    for info in ACTIONS {
+      let (key_names, line) = match entries.iter().find(|entry| entry.key == info.name) {
+        Some(entry) => (key_names(entry)?, Some(entry.line)),
+        None => (info.default_keys.iter().map(|&name| name.to_string()).collect(), None)
+      };
+
+      for key_name in key_names {
+        let key = keys::parse_key(&key_name).ok_or_else(|| {
+          format!("line {}: unknown key '{}' for {}", line.unwrap_or(0), key_name, info.name)
+        })?;
+        if let Some(other) = keymap.bindings.iter().find(|other| same_key(&other.key, &key)) {
+          let mut other_name = ACTIONS.iter().find(|info| info.action == other.action).unwrap().name.to_string();
+          if let (Some(_), Some(other_line)) = (line, other.line) {
+            other_name = format!("{} (line {})", other_name, other_line);
+          }
+          let message = format!("'{}' is bound to both {} and {}", key_name, other_name, info.name);
+          // Point at whichever of the two bindings came from the file
+          return Err(match (line, other.line) {
+            (Some(line), _) | (None, Some(line)) => format!("line {}: {}", line, message),
+            (None, None) => message
+          });
+        }
+        keymap.bindings.push(Binding { key: key, key_name: key_name, action: info.action, line: line });
+      }
+    }
+    Ok(keymap)
+  }
+
+  pub fn action_for(&self, pressed: &Key) -> Option<Action> {
+    self.bindings.iter()
+      .find(|binding| is_press_of(pressed, &binding.key))
+      .map(|binding| binding.action)
+  }
+
+  /* Every action by name along with the names of its keys, in `ACTIONS` order */
+  pub fn action_keys(&self) -> Vec<(&'static str, Vec<String>)> {
+    ACTIONS.iter().map(|info| {
+      // Written the way key scripts spell them, e.g. "hash" rather than "#"
+      let key_names = self.bindings.iter()
+        .filter(|binding| binding.action == info.action)
+        .map(|binding| keys::key_name(&binding.key).unwrap_or_else(|| binding.key_name.clone()))
+        .collect();
+      (info.name, key_names)
+    }).collect()
+  }
+
+  /* One line per action, listing its keys */
+  pub fn help_lines(&self) -> Vec<String> {
+    ACTIONS.iter().map(|info| {
+      let key_names: Vec<&str> = self.bindings.iter()
+        .filter(|binding| binding.action == info.action)
+        .map(|binding| binding.key_name.as_ref())
+        .collect();
+      let keys_text = if key_names.is_empty() { "(unbound)".to_string() } else { key_names.join(", ") };
+      format!("{:<20} {}", info.description, keys_text)
+    }).collect()
+  }
+}
+
+fn key_names(entry: &Entry) -> Result<Vec<String>, String> {
+  let error = || format!("line {}: {} must be a key name or a list of key names", entry.line, entry.key);
+  match entry.value {
+    Value::Str(ref name) => Ok(vec![name.clone()]),
+    Value::Array(ref values) => {
+      values.iter().map(|value| match *value {
+        Value::Str(ref name) => Ok(name.clone()),
+        _ => Err(error())
+      }).collect()
+    }
+    _ => Err(error())
+  }
+}
+
+/* Printable keys are told apart by their character, since the key code of e.g. '>' depends on
+ * the keyboard layout. Everything else by its key code.
+ */
+fn is_press_of(pressed: &Key, binding: &Key) -> bool {
+  let same_key = if binding.code == KeyCode::Char {
+    pressed.printable == binding.printable
+  } else {
+    pressed.code == binding.code
+  };
+  same_key && pressed.alt == binding.alt
+}
+
+fn same_key(a: &Key, b: &Key) -> bool {
+  a.code == b.code && a.printable == b.printable && a.alt == b.alt
+}
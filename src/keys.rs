@@ -0,0 +1,178 @@
+use std::fs::File;
+use std::io::{self, Read};
+use tcod::input::{Key, KeyCode};
+
+/* A single key press or mouse click. Clicks are in console cells. */
+#[derive(Clone, Copy, Debug)]
+pub enum Input {
+  Key(Key),
+  Click { x: i32, y: i32, right: bool }
+}
+
+/* A queue of inputs read from a script, used in place of the window's event loop when the
+ * game runs headless.
+ *
+ * The script is a whitespace separated list of input names (see `parse_input`). Anything
+ * after a '#' on a line is a comment, so the '#' key itself is written "hash".
+ */
+pub struct KeyScript {
+  inputs: Vec<Input>,
+  next: usize
+}
+
+impl KeyScript {
+  pub fn parse(text: &str) -> Result<Self, String> {
+    let mut inputs = vec![];
+    for (line_idx, line) in text.lines().enumerate() {
+      let line = match line.find('#') {
+        Some(comment_start) => &line[..comment_start],
+        None => line
+      };
+      for name in line.split_whitespace() {
+        match parse_input(name) {
+          Some(input) => inputs.push(input),
+          None => return Err(format!("line {}: unknown input '{}'", line_idx + 1, name))
+        }
+      }
+    }
+    Ok(KeyScript::from_inputs(inputs))
+  }
+
+  pub fn from_file(path: &str) -> Result<Self, String> {
+    let file = File::open(path)
+      .map_err(|err| format!("unable to open key script {}: {}", path, err))?;
+    KeyScript::from_reader(file, path)
+  }
+
+  pub fn from_stdin() -> Result<Self, String> {
+    KeyScript::from_reader(io::stdin(), "<stdin>")
+  }
+
+  pub fn from_inputs(inputs: Vec<Input>) -> Self {
+    KeyScript { inputs: inputs, next: 0 }
+  }
+
+  fn from_reader<R: Read>(mut reader: R, source_name: &str) -> Result<Self, String> {
+    let mut text = String::new();
+    reader.read_to_string(&mut text)
+      .map_err(|err| format!("unable to read key script {}: {}", source_name, err))?;
+    KeyScript::parse(&text).map_err(|err| format!("{}: {}", source_name, err))
+  }
+
+  pub fn is_finished(&self) -> bool {
+    self.next >= self.inputs.len()
+  }
+
+  /* Returns None once every input in the script has been consumed */
+  pub fn next_input(&mut self) -> Option<Input> {
+    let input = self.inputs.get(self.next).cloned();
+    if input.is_some() {
+      self.next += 1;
+    }
+    input
+  }
+}
+
+const NAMED_KEYS: &'static [(&'static str, KeyCode)] = &[
+  ("up", KeyCode::Up),
+  ("down", KeyCode::Down),
+  ("left", KeyCode::Left),
+  ("right", KeyCode::Right),
+  ("home", KeyCode::Home),
+  ("end", KeyCode::End),
+  ("pageup", KeyCode::PageUp),
+  ("pagedown", KeyCode::PageDown),
+  ("numpad1", KeyCode::NumPad1),
+  ("numpad2", KeyCode::NumPad2),
+  ("numpad3", KeyCode::NumPad3),
+  ("numpad4", KeyCode::NumPad4),
+  ("numpad5", KeyCode::NumPad5),
+  ("numpad6", KeyCode::NumPad6),
+  ("numpad7", KeyCode::NumPad7),
+  ("numpad8", KeyCode::NumPad8),
+  ("numpad9", KeyCode::NumPad9),
+  ("enter", KeyCode::Enter),
+  ("escape", KeyCode::Escape),
+  ("space", KeyCode::Spacebar),
+];
+
+// '#' starts a comment in key scripts, so it goes by a name instead
+const HASH_KEY_NAME: &'static str = "hash";
+
+/* Converts an input name into an `Input`. Clicks are written as "click@x,y" for the left
+ * button and "rclick@x,y" for the right one, at a screen cell (not a map tile, as the view
+ * scrolls); anything else is a key name.
+ */
+pub fn parse_input(name: &str) -> Option<Input> {
+  let (right, cell) = if name.starts_with("click@") {
+    (false, &name[6..])
+  } else if name.starts_with("rclick@") {
+    (true, &name[7..])
+  } else {
+    return parse_key(name).map(Input::Key);
+  };
+
+  let mut coords = cell.split(',').map(|coord| coord.parse::<i32>());
+  match (coords.next(), coords.next(), coords.next()) {
+    (Some(Ok(x)), Some(Ok(y)), None) => Some(Input::Click { x: x, y: y, right: right }),
+    _ => None
+  }
+}
+
+/* The inverse of `parse_input` */
+pub fn input_name(input: &Input) -> Option<String> {
+  match *input {
+    Input::Key(ref key) => key_name(key),
+    Input::Click { x, y, right } => {
+      Some(format!("{}click@{},{}", if right { "r" } else { "" }, x, y))
+    }
+  }
+}
+
+/* Converts a key name into a tcod key press. Names are either one of `NAMED_KEYS`, "hash" or
+ * a single printable character, optionally prefixed with "alt+" (e.g. "alt+enter").
+ */
+pub fn parse_key(name: &str) -> Option<Key> {
+  let (alt, name) = if name.starts_with("alt+") {
+    (true, &name[4..])
+  } else {
+    (false, name)
+  };
+
+  let mut key = Key { pressed: true, alt: alt, left_alt: alt, ..Default::default() };
+
+  if let Some(&(_, code)) = NAMED_KEYS.iter().find(|&&(key_name, _)| key_name == name) {
+    key.code = code;
+    if code == KeyCode::Spacebar {
+      key.printable = ' ';
+    }
+    return Some(key);
+  }
+
+  let name = if name == HASH_KEY_NAME { "#" } else { name };
+  let mut chars = name.chars();
+  match (chars.next(), chars.next()) {
+    (Some(c), None) if !c.is_whitespace() && !c.is_control() => {
+      key.code = KeyCode::Char;
+      key.printable = c;
+      Some(key)
+    }
+    _ => None
+  }
+}
+
+/* The inverse of `parse_key`. Returns None for keys that can't be written to a script. */
+pub fn key_name(key: &Key) -> Option<String> {
+  let prefix = if key.alt { "alt+" } else { "" };
+  if let Some(&(name, _)) = NAMED_KEYS.iter().find(|&&(_, code)| code == key.code) {
+    return Some(format!("{}{}", prefix, name));
+  }
+  let c = key.printable;
+  if c == '#' {
+    return Some(format!("{}{}", prefix, HASH_KEY_NAME));
+  }
+  if c != '\0' && !c.is_whitespace() && !c.is_control() {
+    return Some(format!("{}{}", prefix, c));
+  }
+  None
+}
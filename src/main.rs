@@ -3,37 +3,59 @@ extern crate rand;
 
 use std::env;
 use std::cmp;
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::ascii::AsciiExt;
-use rand::{Rng, SeedableRng, StdRng};
+use rand::Rng;
 use tcod::console::*;
 use tcod::colors::{self, Color};
 use tcod::map::{Map as FovMap, FovAlgorithm};
 use tcod::input::{self, Event, Key, Mouse};
+use content::{AiKind, Content};
+use keys::Input;
+use keymap::{Action, Keymap};
 
 mod components;
+mod config;
+mod content;
+mod keymap;
+mod keys;
+mod mapgen;
+mod pathfinding;
+mod replay;
+mod rng;
+mod save;
 
 const SCREEN_WIDTH: i32 = 80;
 const SCREEN_HEIGHT: i32 = 43;
 const LIMIT_FPS: i32 = 30;
 
-const MAP_WIDTH: i32 = SCREEN_WIDTH;
-const MAP_HEIGHT: i32 = SCREEN_HEIGHT - 5;
+// Levels can be bigger than the screen; the camera scrolls to keep the player in view
+const MAP_WIDTH: i32 = 120;
+const MAP_HEIGHT: i32 = 70;
+const VIEW_WIDTH: i32 = SCREEN_WIDTH;
+const VIEW_HEIGHT: i32 = PANEL_Y; // Everything above the panel
 
 const BAR_WIDTH: i32 = 20;
 const PANEL_HEIGHT: i32 = 7;
 const PANEL_Y: i32 = SCREEN_HEIGHT - PANEL_HEIGHT;
 const INVENTORY_WIDTH: i32 = 50;
+const LEVEL_SCREEN_WIDTH: i32 = 40;
+const HELP_SCREEN_WIDTH: i32 = 50;
 
-const MSG_X: i32 = BAR_WIDTH + 2;
-const MSG_WIDTH: i32 = SCREEN_WIDTH - BAR_WIDTH - 2;
+const STATUS_X: i32 = BAR_WIDTH + 2; // One letter per status effect, right of the HP bar
+const STATUS_WIDTH: i32 = 6;
+
+const MSG_X: i32 = STATUS_X + STATUS_WIDTH;
+const MSG_WIDTH: i32 = SCREEN_WIDTH - MSG_X;
 const MSG_HEIGHT: usize = PANEL_HEIGHT as usize - 1;
 
-const ROOM_MAX_SIZE: i32 = 12;
-const ROOM_MIN_SIZE: i32 = 5;
-// @feature min_rooms
-const MAX_ROOMS: i32 = 10;
-const MAX_ROOM_MONSTERS: i32 = 4;
-const MAX_ROOM_ITEMS: i32 = 2;
+const MAX_MAP_ATTEMPTS: i32 = 100; // Before giving up on generating a playable level
+// Most monsters and items a room can get, from each depth down. See `content::value_at_depth`.
+const MAX_ROOM_MONSTERS: &'static [(i32, i32)] = &[(1, 2), (3, 3), (5, 4), (7, 5)];
+const MAX_ROOM_ITEMS: &'static [(i32, i32)] = &[(1, 1), (4, 2)];
+const MAX_SPAWN_TRIES: i32 = 20; // Random tiles tried for each spawn before giving up on it
+const MIN_MONSTER_SPAWN_DISTANCE: f32 = 5.0; // From where the player starts the level
 
 const PLAYER_IDX: usize = 0; // Always the first object
 
@@ -41,14 +63,62 @@ const FOV_ALGO: FovAlgorithm = FovAlgorithm::Basic;
 const FOV_LIGHT_WALLS: bool = true;
 const TORCH_RADIUS: i32 = 10;
 
+// Extra path cost of stepping through a tile someone is standing on. They'll likely have
+// moved by the time we get there, so it's only worth a short detour to avoid them.
+const PATH_OCCUPIED_TILE_COST: u32 = 50;
+
+// How many turns a monster keeps hunting after losing sight of the player
+const CHASE_TURNS: i32 = 15;
+// Monsters run once their HP drops to this percentage of their max HP
+const FLEE_HP_PERCENT: i32 = 25;
+
+// Energy costs of actions. A creature can act once it has ACTION_ENERGY, so at the player's
+// speed of PLAYER_SPEED a move takes ACTION_ENERGY / PLAYER_SPEED ticks.
+const ACTION_ENERGY: i32 = 100;
+const MOVE_COST: i32 = 100; // Also what standing still costs
+const ATTACK_COST: i32 = 100;
+const USE_ITEM_COST: i32 = 50;
+const EQUIP_COST: i32 = 150;
+const PLAYER_SPEED: i32 = 10;
+
 const COLOR_DARK_WALL: Color = Color { r: 0, g: 0, b: 100 };
 const COLOR_LIGHT_WALL: Color = Color { r: 130, g: 100, b: 90 };
 const COLOR_DARK_GROUND: Color = Color { r: 50, g: 50, b: 150 };
 const COLOR_LIGHT_GROUND: Color = Color { r: 180, g: 160, b: 108 };
+const COLOR_PLANNED_PATH: Color = Color { r: 90, g: 140, b: 200 };
 
 const DEFAULT_DEATH_CHAR: char = 'x';
 
+const STAIRS_NAME: &'static str = "Stairs";
+
 const HEAL_AMOUNT: i32 = 8;
+const LIGHTNING_DAMAGE: i32 = 20;
+const LIGHTNING_RANGE: f32 = 5.0;
+const FIREBALL_DAMAGE: i32 = 12;
+const FIREBALL_RADIUS: f32 = 3.0;
+const FIREBALL_RANGE: f32 = 10.0;
+const CONFUSE_RANGE: f32 = 8.0;
+const CONFUSE_NUM_TURNS: i32 = 10;
+const REGENERATE_AMOUNT: i32 = 1; // Per turn
+const REGENERATE_NUM_TURNS: i32 = 20;
+const HASTE_SPEED_BONUS: i32 = PLAYER_SPEED; // Doubles the player's base speed
+const HASTE_NUM_TURNS: i32 = 15;
+const POISON_DAMAGE: i32 = 1; // Per turn
+const POISON_NUM_TURNS: i32 = 8;
+
+const TARGETING_PROMPT: &'static str =
+  "Choose a target with the movement keys and Enter or a left click. Escape or right click cancels.";
+
+// Attack rolls are percentile: 1 to 100, low is good
+const BASE_HIT_CHANCE: i32 = 90; // Before the target's evasion
+const MIN_HIT_CHANCE: i32 = 5;
+const MAX_HIT_CHANCE: i32 = 95;
+const CRIT_CHANCE: i32 = 5; // Rolls this low always hit, with the damage dice rolled twice
+const GRAZE_MARGIN: i32 = 15; // Hits rolled this close to missing deal half damage
+
+// XP needed to go from level N to N + 1 is LEVEL_UP_BASE + N * LEVEL_UP_FACTOR
+const LEVEL_UP_BASE: i32 = 200;
+const LEVEL_UP_FACTOR: i32 = 150;
 
 /* Mutably borrow two *separate elements from the given slice.
  * Panics when the indexes are equal or out of bounds.
@@ -78,12 +148,149 @@ impl MessageLog for Vec<(String, Color)> {
 }
 
 
-struct EngineState {
+struct Display {
   root: Root,
   con: Offscreen,
   panel: Offscreen,
+  planned_path: Vec<(i32, i32)> // Where the player is travelling, drawn over the map
+}
+
+/* Which part of the map is on screen: the view's top left corner, in map coordinates. It's
+ * centred on the player except near the edges of the map, where it stops scrolling.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Camera {
+  x: i32,
+  y: i32
+}
+
+impl Camera {
+  pub fn following(target: &Object) -> Self {
+    let clamp = |pos: i32, view_size: i32, map_size: i32| {
+      cmp::max(0, cmp::min(map_size - view_size, pos - view_size / 2))
+    };
+    Camera {
+      x: clamp(target.x, VIEW_WIDTH, MAP_WIDTH),
+      y: clamp(target.y, VIEW_HEIGHT, MAP_HEIGHT)
+    }
+  }
+
+  /* None when the tile is off screen */
+  pub fn to_screen(&self, x: i32, y: i32) -> Option<(i32, i32)> {
+    let (screen_x, screen_y) = (x - self.x, y - self.y);
+    if screen_x >= 0 && screen_x < VIEW_WIDTH && screen_y >= 0 && screen_y < VIEW_HEIGHT {
+      Some((screen_x, screen_y))
+    } else {
+      None
+    }
+  }
+
+  /* None when the screen cell isn't showing the map, e.g. it's part of the panel */
+  pub fn to_map(&self, screen_x: i32, screen_y: i32) -> Option<(i32, i32)> {
+    let (x, y) = (screen_x + self.x, screen_y + self.y);
+    let on_view = screen_x >= 0 && screen_x < VIEW_WIDTH && screen_y >= 0 && screen_y < VIEW_HEIGHT;
+    if on_view && x < MAP_WIDTH && y < MAP_HEIGHT {
+      Some((x, y))
+    } else {
+      None
+    }
+  }
+}
+
+/* Where key presses come from. Headless runs read them from a script instead of a window. */
+enum InputSource {
+  Window,
+  Script(keys::KeyScript)
+}
+
+struct EngineState {
+  display: Option<Display>, // None when running headless
+  input: InputSource,
+  recorder: Option<replay::Recorder>,
   fov: FovMap,
-  mouse: Mouse
+  mouse: Mouse,
+  keymap: Keymap
+}
+
+impl EngineState {
+  pub fn is_headless(&self) -> bool {
+    self.display.is_none()
+  }
+
+  pub fn is_window_closed(&self) -> bool {
+    self.display.as_ref().map_or(false, |display| display.root.window_closed())
+  }
+
+  /* Replays and key scripts. These never touch the player's save files. */
+  pub fn is_scripted(&self) -> bool {
+    match self.input {
+      InputSource::Window => false,
+      InputSource::Script(_) => true
+    }
+  }
+
+  /* Only a key script can run out of input */
+  pub fn is_out_of_input(&self) -> bool {
+    match self.input {
+      InputSource::Window => false,
+      InputSource::Script(ref script) => script.is_finished()
+    }
+  }
+
+  /* Polls for the next input without blocking. Returns None when a scripted input source has
+   * run out of inputs.
+   *
+   * Idle frames and mouse movement come back as an empty key; the latest mouse state is kept
+   * in `mouse` either way.
+   */
+  pub fn poll_input(&mut self) -> Option<Input> {
+    let input = match self.input {
+      InputSource::Window => {
+        match input::check_for_event(input::MOUSE | input::KEY_PRESS) {
+          Some((_, Event::Mouse(m))) => {
+            self.mouse = m;
+            let (x, y) = (m.cx as i32, m.cy as i32);
+            if m.lbutton_pressed {
+              Some(Input::Click { x: x, y: y, right: false })
+            } else if m.rbutton_pressed {
+              Some(Input::Click { x: x, y: y, right: true })
+            } else {
+              Some(Input::Key(Default::default()))
+            }
+          }
+          Some((_, Event::Key(k))) => Some(Input::Key(k)),
+          _ => Some(Input::Key(Default::default())),
+        }
+      }
+      InputSource::Script(ref mut script) => script.next_input()
+    };
+    if let (Some(ref mut recorder), Some(ref input)) = (self.recorder.as_mut(), input) {
+      recorder.record_input(input);
+    }
+    input
+  }
+
+  /* Blocks until a key is pressed. A script that has run out of inputs, or whose next input
+   * is a click, produces an empty key.
+   */
+  pub fn wait_for_key(&mut self) -> Key {
+    let input = match self.input {
+      InputSource::Script(ref mut script) => {
+        script.next_input().unwrap_or(Input::Key(Default::default()))
+      }
+      InputSource::Window => Input::Key(match self.display {
+        Some(ref mut display) => display.root.wait_for_keypress(true),
+        None => Default::default()
+      })
+    };
+    if let Some(ref mut recorder) = self.recorder {
+      recorder.record_input(&input);
+    }
+    match input {
+      Input::Key(key) => key,
+      Input::Click { .. } => Default::default()
+    }
+  }
 }
 
 struct GameState {
@@ -92,45 +299,84 @@ struct GameState {
   log: Messages,
   game_running: bool,
   inventory: Vec<Object>,
-  map: Map
+  map: Map,
+  depth: i32,
+  mapgen: Option<mapgen::MapGenKind>, // From --mapgen when the game started. None picks one per level.
+  content: Content // Loaded from data/content.toml, not saved
 }
 
 
 struct ThreadContext {
-  rand: StdRng,
+  rand: rng::Pcg32,
   custom_seed: bool,
-  rand_seed: i32
+  rand_seed: u64
 }
 
-fn _new_thread_context_from_seed(seed_val: i32, rng_seed: &[usize], custom_seed: bool) -> ThreadContext {
-    let rng: StdRng = SeedableRng::from_seed(rng_seed);
-    // TODO @bug OSX: if you take a printed seed and pass it in as an arg, you don't get
-    // the exact same RNG as the one that printed the seed value. I also had to add the
-    // `as i32` in the println in order to see an integer value in OSX. This wasn't needed
-    // in Windows.
-    println!("[RNG init] Provided seed: {:?}, RNG Seed: {:?}", seed_val, rng_seed[0] as i32);
-    ThreadContext {
-      rand: rng,
-      custom_seed: custom_seed,
-      rand_seed: rng_seed[0] as i32
-    }
+fn _new_thread_context_from_seed(seed: u64, custom_seed: bool) -> ThreadContext {
+  // Printed in the form --seed expects so that any run can be reproduced
+  println!("[RNG init] Seed: {} (run with --seed {} to reproduce)", seed, seed);
+  ThreadContext {
+    rand: rng::Pcg32::from_seed(seed),
+    custom_seed: custom_seed,
+    rand_seed: seed
+  }
 }
 
 impl ThreadContext {
+  /* Seeds the RNG from the system clock */
   pub fn new() -> Self {
-    let seed = 69; // default seed value
-    let rng_seed: &[_] = &[&seed as *const i32 as usize];
-    _new_thread_context_from_seed(seed, rng_seed, false)
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Default::default());
+    let seed = now.as_secs().wrapping_mul(1_000_000_000).wrapping_add(now.subsec_nanos() as u64);
+    _new_thread_context_from_seed(seed, false)
+  }
+
+  pub fn from_seed(seed: u64) -> Self {
+    _new_thread_context_from_seed(seed, true)
+  }
+
+  /* Rolls `dice` and returns their total */
+  pub fn roll(&mut self, dice: components::Dice) -> i32 {
+    (0..dice.count).map(|_| self.rand.gen_range(1, dice.sides + 1)).sum()
+  }
+
+  /* A number from 1 to 100 */
+  pub fn roll_percent(&mut self) -> i32 {
+    self.rand.gen_range(1, 101)
   }
 
-  pub fn from_seed(seed: i32) -> Self {
-    let rng_seed: &[_] = &[seed as usize];
-    _new_thread_context_from_seed(seed, rng_seed, true)
+  /* Picks one of `choices` at random, each as likely as its share of the total weight. Returns
+   * None when there's nothing to pick, i.e. every weight is 0.
+   */
+  pub fn pick_weighted<'a, T, F>(&mut self, choices: &'a [T], weight: F) -> Option<&'a T>
+    where F: Fn(&T) -> u32 {
+    let total_weight: u32 = choices.iter().map(|choice| weight(choice)).sum();
+    if total_weight == 0 {
+      return None;
+    }
+
+    let mut roll = self.rand.gen_range(0, total_weight);
+    for choice in choices {
+      let choice_weight = weight(choice);
+      if roll < choice_weight {
+        return Some(choice);
+      }
+      roll -= choice_weight;
+    }
+    None
+  }
+
+  /* Resumes a saved game's RNG exactly where it left off */
+  pub fn restore(rand_seed: u64, custom_seed: bool, rand: rng::Pcg32) -> Self {
+    ThreadContext {
+      rand: rand,
+      custom_seed: custom_seed,
+      rand_seed: rand_seed
+    }
   }
 }
 
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 struct Object {
   x: i32,
   y: i32,
@@ -141,11 +387,17 @@ struct Object {
   blocks: bool,
   alive: bool,
   show_when_dead: bool,
+  always_visible: bool, // Drawn outside the FOV once its tile has been explored
+  is_player: bool, // Owns `GameState::inventory` and wears the gear equipped from it
+  is_stairs: bool, // Only set by `make_map`, so content can't make more of them
+  path: Vec<(i32, i32)>, // Steps cached by `move_towards`, in walking order
 
   // components
   char_attributes: Option<components::CharacterAttributes>,
   brain: Option<components::Ai>,
   item: Option<components::Item>,
+  equipment: Option<components::Equipment>,
+  status_effects: Vec<components::StatusEffect>,
 }
 
 impl Object {
@@ -161,13 +413,24 @@ impl Object {
       blocks: blocks,
       alive: false,
       show_when_dead: show_dead,
+      always_visible: false,
+      is_player: false,
+      is_stairs: false,
+      path: vec![],
 
       char_attributes: None,
       brain: None,
-      item: None
+      item: None,
+      equipment: None,
+      status_effects: vec![]
     }
   }
 
+  /* Items and gear; gear has no `item` component */
+  pub fn can_be_picked_up(&self) -> bool {
+    self.item.is_some() || self.equipment.is_some()
+  }
+
   pub fn pos(&self) -> (i32, i32) {
     (self.x, self.y)
   }
@@ -183,8 +446,15 @@ impl Object {
     ((dx.pow(2) + dy.pow(2)) as f32).sqrt()
   }
 
+  pub fn distance(&self, x: i32, y: i32) -> f32 {
+    let dx = x - self.x;
+    let dy = y - self.y;
+    ((dx.pow(2) + dy.pow(2)) as f32).sqrt()
+  }
+
+  /* Returns the XP granted for the kill when the damage kills this object */
   // @incomplete switch to f32 for damage/health, etc
-  pub fn take_damage(&mut self, game: &mut GameState, damage: i32) {
+  pub fn take_damage(&mut self, game: &mut GameState, damage: i32) -> Option<i32> {
     if self.alive && damage > 0 {
       if let Some(ref mut char_attributes) = self.char_attributes {
         char_attributes.hp -= cmp::min(damage, char_attributes.hp);
@@ -195,9 +465,11 @@ impl Object {
       if let Some(char_attributes) = self.char_attributes {
         if !self.alive {
           on_object_death(self, game);
+          return Some(char_attributes.xp);
         }
       }
     }
+    None
   }
 
   // @incomplete switch to f32 for damage/health, etc
@@ -209,14 +481,118 @@ impl Object {
     }
   }
 
-  pub fn attack(&mut self, target: &mut Object, game: &mut GameState) {
-    let damage = self.char_attributes.map_or(0, |x| x.power) -
-                 target.char_attributes.map_or(0, |x| x.defense);
-    if damage > 0 {
-      game.log.add(format!("{} attacks {} and deals {} damage!", self.name, target.name, damage), colors::WHITE);
-      target.take_damage(game, damage);
+  /* Base power plus the bonuses of any equipped gear */
+  pub fn power(&self, game: &GameState) -> i32 {
+    let base = self.char_attributes.map_or(0, |x| x.power);
+    base + self.equipped_gear(game).iter().map(|gear| gear.power_bonus).sum::<i32>()
+  }
+
+  /* Base defense plus the bonuses of any equipped gear */
+  pub fn defense(&self, game: &GameState) -> i32 {
+    let base = self.char_attributes.map_or(0, |x| x.defense);
+    base + self.equipped_gear(game).iter().map(|gear| gear.defense_bonus).sum::<i32>()
+  }
+
+  fn equipped_gear(&self, game: &GameState) -> Vec<components::Equipment> {
+    if !self.is_player {
+      return vec![];
+    }
+    game.inventory.iter()
+      .filter_map(|item| item.equipment)
+      .filter(|gear| gear.equipped)
+      .collect()
+  }
+
+  /* Base speed plus any haste */
+  pub fn speed(&self) -> i32 {
+    let base = self.char_attributes.map_or(0, |x| x.speed);
+    base + self.status_effects.iter()
+      .filter(|effect| effect.kind == components::StatusKind::Haste)
+      .map(|effect| effect.magnitude)
+      .sum::<i32>()
+  }
+
+  pub fn has_status(&self, kind: components::StatusKind) -> bool {
+    self.status_effects.iter().any(|effect| effect.kind == kind)
+  }
+
+  /* Applies `effect`, combining it with one of the same kind according to `StatusKind::stacks` */
+  pub fn add_status(&mut self, effect: components::StatusEffect) {
+    match self.status_effects.iter_mut().find(|existing| existing.kind == effect.kind) {
+      Some(existing) => {
+        existing.turns_left = if effect.kind.stacks() {
+          existing.turns_left + effect.turns_left
+        } else {
+          cmp::max(existing.turns_left, effect.turns_left)
+        };
+        existing.magnitude = cmp::max(existing.magnitude, effect.magnitude);
+      }
+      None => self.status_effects.push(effect)
+    }
+  }
+
+  pub fn has_energy_to_act(&self) -> bool {
+    self.char_attributes.map_or(false, |x| x.energy >= ACTION_ENERGY)
+  }
+
+  pub fn spend_energy(&mut self, cost: i32) {
+    if let Some(ref mut char_attributes) = self.char_attributes {
+      char_attributes.energy -= cost;
+    }
+  }
+
+  /* Rolls to hit against the target's evasion, then rolls the damage of whatever landed */
+  pub fn attack(&mut self, target: &mut Object, game: &mut GameState, thread_ctx: &mut ThreadContext) {
+    let evasion = target.char_attributes.map_or(0, |x| x.evasion);
+    let hit_chance = cmp::max(MIN_HIT_CHANCE, cmp::min(MAX_HIT_CHANCE, BASE_HIT_CHANCE - evasion));
+    let roll = thread_ctx.roll_percent();
+    let outcome = if roll <= CRIT_CHANCE {
+      AttackOutcome::Critical
+    } else if roll > hit_chance {
+      AttackOutcome::Miss
+    } else if roll > hit_chance - GRAZE_MARGIN {
+      AttackOutcome::Graze
     } else {
-      game.log.add(format!("{} attacks {}, but it has no effect!", self.name, target.name), colors::WHITE);
+      AttackOutcome::Hit
+    };
+
+    if outcome == AttackOutcome::Miss {
+      game.log.add(format!("{} attacks {}, but misses.", self.name, target.name), colors::LIGHT_GREY);
+      return;
+    }
+
+    let dice = self.char_attributes.map_or(components::Dice { count: 1, sides: 1 }, |x| x.damage);
+    let mut rolled = thread_ctx.roll(dice);
+    if outcome == AttackOutcome::Critical {
+      rolled += thread_ctx.roll(dice);
+    }
+    let mut damage = cmp::max(1, rolled + self.power(game) - target.defense(game));
+    if outcome == AttackOutcome::Graze {
+      damage = cmp::max(1, damage / 2);
+    }
+
+    match outcome {
+      AttackOutcome::Critical => {
+        game.log.add(format!("{} lands a critical hit on {} for {} damage!", self.name, target.name, damage),
+                     colors::ORANGE);
+      }
+      AttackOutcome::Graze => {
+        game.log.add(format!("{} grazes {} for {} damage.", self.name, target.name, damage), colors::WHITE);
+      }
+      _ => {
+        game.log.add(format!("{} attacks {} and deals {} damage!", self.name, target.name, damage), colors::WHITE);
+      }
+    }
+    if let Some(xp) = target.take_damage(game, damage) {
+      if let Some(ref mut char_attributes) = self.char_attributes {
+        char_attributes.xp += xp;
+      }
+    }
+    if let Some(effect) = self.char_attributes.and_then(|x| x.on_hit) {
+      if target.alive {
+        game.log.add(format!("{} is {}!", target.name, effect.kind.adjective()), colors::LIGHT_GREEN);
+        target.add_status(effect);
+      }
     }
   }
 
@@ -266,7 +642,7 @@ impl Rect {
 }
 
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 struct Tile {
   // @future try using Object for tiles. Can then reuse HP, damage given, etc.
   passable: bool,
@@ -296,10 +672,21 @@ impl Tile {
 
 type Map = Vec<Tile>;
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum AttackOutcome {
+  Miss,
+  Graze,
+  Hit,
+  Critical
+}
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 enum PlayerAction {
   TookTurn,
+  TookTurns(u32), // Already ran the monsters after each one, e.g. while auto-exploring
   DidntTakeTurn,
+  Descend,
+  SaveGame,
   Exit,
 }
 
@@ -318,70 +705,87 @@ struct TileCollisionInfo {
     game.log.remove(0);
 */
 
-/* Places a rect of empty tiles into `map` */
-fn create_room(room: Rect, map: &mut Map) {
-  for y in (room.y1 + 1)..room.y2 {
-    for x in (room.x1 + 1)..room.x2 {
-      map[(y * MAP_WIDTH + x) as usize] = Tile::empty();
-    }
-  }
-}
-
-fn create_h_tunnel(x1: i32, x2: i32, y: i32, map: &mut Map) {
-  for x in cmp::min(x1, x2)..(cmp::max(x1, x2) + 1) {
-    Tile::make_empty(&mut map[(y * MAP_WIDTH + x) as usize]);
-  }
-}
+/* Generates a level with `mapgen`, or a random generator when it's None, and fills it.
+ *
+ * Levels that fail `mapgen::validate` are thrown away and generated again. That only draws
+ * more numbers from `thread_ctx`, so a seed still always produces the same levels. A generator
+ * that keeps failing is swapped for the rooms one rather than ending the run.
+ */
+fn make_map(content: &Content, depth: i32, mapgen: Option<mapgen::MapGenKind>,
+            thread_ctx: &mut ThreadContext, objects: &mut Vec<Object>) -> Map {
+  let kind = match mapgen {
+    Some(kind) => kind,
+    None => mapgen::MapGenKind::random(thread_ctx)
+  };
 
-fn create_v_tunnel(y1: i32, y2: i32, x: i32, map: &mut Map) {
-  for y in cmp::min(y1, y2)..(cmp::max(y1, y2) + 1) {
-    Tile::make_empty(&mut map[(y * MAP_WIDTH + x) as usize]);
+  let mut kinds = vec![kind];
+  if kind != mapgen::MapGenKind::Rooms {
+    kinds.push(mapgen::MapGenKind::Rooms);
   }
-}
 
-fn make_map(thread_ctx: &mut ThreadContext, objects: &mut Vec<Object>) -> Map {
-  let mut map = vec![Tile::wall(); (MAP_WIDTH * MAP_HEIGHT) as usize];
-  let mut rooms = vec![];
+  let num_objects_before = objects.len();
+  for &kind in &kinds {
+    for attempt in 1..(MAX_MAP_ATTEMPTS + 1) {
+      let mut layout = kind.generate(thread_ctx);
+      if let Err(reason) = mapgen::validate(&mut layout) {
+        println!("[mapgen] Regenerating level {} ({}, attempt {}): {}", depth, kind.name(), attempt, reason);
+        continue;
+      }
 
-  // @improvement Create a sparse tile map.
+      let (start_x, start_y) = layout.player_start;
+      objects[PLAYER_IDX].set_pos(start_x, start_y);
+      let mut num_failed_spawns = 0;
+      for room in &layout.rooms {
+        num_failed_spawns += place_objects(content, depth, thread_ctx, *room, &layout.map, objects);
+      }
+      if num_failed_spawns > 0 {
+        let num_spawned = objects.len() - num_objects_before;
+        println!("[mapgen] Level {}: spawned {} monsters and items, {} found no free tile",
+                 depth, num_spawned, num_failed_spawns);
+      }
 
-  for i in 0..MAX_ROOMS {
-    let w = thread_ctx.rand.gen_range(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
-    let h = thread_ctx.rand.gen_range(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
-    let x = thread_ctx.rand.gen_range(0, MAP_WIDTH - w);
-    let y = thread_ctx.rand.gen_range(0, MAP_HEIGHT - h);
+      // Validating walled off everything the player can't walk to and spawns only go on open
+      // tiles, so every spawn can be reached
+      debug_assert!({
+        let distances = mapgen::walking_distances(&layout.map, layout.player_start);
+        objects[num_objects_before..].iter().all(|obj| distances[(obj.y * MAP_WIDTH + obj.x) as usize].is_some())
+      });
 
-    let room = Rect::new(x, y, w, h);
-    let can_place = !rooms.iter().any(|other_room| room.intersects_with(other_room));
+      let (stairs_x, stairs_y) = layout.stairs;
+      let mut stairs = Object::new(stairs_x, stairs_y, '>', '>', STAIRS_NAME, colors::WHITE, false, true);
+      stairs.always_visible = true;
+      stairs.is_stairs = true;
+      objects.push(stairs);
 
-    if can_place {
-      create_room(room, &mut map);
+      return layout.map;
+    }
+    println!("[mapgen] Level {}: giving up on the {} generator after {} attempts",
+             depth, kind.name(), MAX_MAP_ATTEMPTS);
+  }
 
-      let (new_x, new_y) = room.center();
+  exit_with_error(&format!("unable to generate a playable level {} in {} attempts per generator",
+                           depth, MAX_MAP_ATTEMPTS));
+}
 
-      if rooms.is_empty() {
-        objects[PLAYER_IDX].set_pos(new_x, new_y);
-      } else {
-        // connect to previous room with a tunnel
-        let (prev_x, prev_y) = rooms[rooms.len() - 1].center();
+/* Generates the next level down. The player and their inventory come along; every other
+ * object belonged to the old level and is dropped.
+ */
+fn next_level(game: &mut GameState, engine: &mut EngineState, thread_ctx: &mut ThreadContext,
+              objects: &mut Vec<Object>) {
+  game.depth += 1;
+  game.log.add(format!("You descend to level {} of the dungeon...", game.depth), colors::RED);
 
-        // draw a coin to pick the type of tunnel
-        if thread_ctx.rand.gen::<bool>() {
-          create_h_tunnel(prev_x, new_x, prev_y, &mut map);
-          create_v_tunnel(prev_y, new_y, new_x, &mut map);
-        } else {
-          create_v_tunnel(prev_y, new_y, prev_x, &mut map);
-          create_h_tunnel(prev_x, new_x, new_y, &mut map);
-        }
-      }
+  objects.truncate(PLAYER_IDX + 1);
+  game.map = make_map(&game.content, game.depth, game.mapgen, thread_ctx, objects);
 
-      place_objects(thread_ctx, room, &map, objects);
+  initialize_fov(game, engine);
+  let (player_x, player_y) = objects[PLAYER_IDX].pos();
+  engine.fov.compute_fov(player_x, player_y, TORCH_RADIUS, FOV_LIGHT_WALLS, FOV_ALGO);
 
-      rooms.push(room);
-    }
+  // Tiles are only drawn once explored, so anything left over from the old level has to go
+  if let Some(ref mut display) = engine.display {
+    display.con.clear();
   }
-
-  map
 }
 
 fn check_tile_for_collision(x: i32, y: i32, map: &Map, objects: &[Object]) -> TileCollisionInfo {
@@ -421,36 +825,278 @@ fn pick_up_item(game: &mut GameState, object_id: usize, objects: &mut Vec<Object
   else {
     let item = objects.swap_remove(object_id);
     game.log.add(format!("You picked up a {}!", item.name), colors::GREEN);
+    let slot = item.equipment.map(|gear| gear.slot);
     game.inventory.push(item);
+
+    // Put gear on right away when its slot is free
+    if let Some(slot) = slot {
+      if equipped_in_slot(slot, &game.inventory).is_none() {
+        let inventory_id = game.inventory.len() - 1;
+        equip(game, inventory_id);
+      }
+    }
+  }
+}
+
+fn equipped_in_slot(slot: components::Slot, inventory: &[Object]) -> Option<usize> {
+  inventory.iter().position(|item| {
+    item.equipment.map_or(false, |gear| gear.equipped && gear.slot == slot)
+  })
+}
+
+fn equip(game: &mut GameState, inventory_id: usize) {
+  let message = {
+    let item = &mut game.inventory[inventory_id];
+    match item.equipment {
+      Some(ref mut gear) if !gear.equipped => {
+        gear.equipped = true;
+        Some(format!("Equipped {} on {}.", item.name, gear.slot))
+      }
+      _ => None
+    }
+  };
+  if let Some(message) = message {
+    game.log.add(message, colors::LIGHT_GREEN);
+  }
+}
+
+fn unequip(game: &mut GameState, inventory_id: usize) {
+  let message = {
+    let item = &mut game.inventory[inventory_id];
+    match item.equipment {
+      Some(ref mut gear) if gear.equipped => {
+        gear.equipped = false;
+        Some(format!("Unequipped {} from {}.", item.name, gear.slot))
+      }
+      _ => None
+    }
+  };
+  if let Some(message) = message {
+    game.log.add(message, colors::LIGHT_YELLOW);
+  }
+}
+
+/* Equips the item, swapping out whatever was in its slot, or takes it off if it's already on */
+fn toggle_equipment(game: &mut GameState, inventory_id: usize) -> ItemUseResult {
+  let gear = match game.inventory[inventory_id].equipment {
+    Some(gear) => gear,
+    None => return ItemUseResult::Cancelled
+  };
+  if gear.equipped {
+    unequip(game, inventory_id);
+  } else {
+    if let Some(current_id) = equipped_in_slot(gear.slot, &game.inventory) {
+      unequip(game, current_id);
+    }
+    equip(game, inventory_id);
   }
+  ItemUseResult::UsedAndKept
 }
 
+#[derive(PartialEq)]
 enum ItemUseResult {
   UsedUp,
+  UsedAndKept,
   Cancelled
 }
 
-fn use_item(game: &mut GameState, inventory_id: usize, objects: &mut Vec<Object>) {
+/* How the player picks what an item is used on */
+enum Targeting {
+  Player,
+  Tile { range: f32 },
+  Monster { range: f32 },
+  ClosestMonster { range: f32 } // Picked automatically, no input needed
+}
+
+/* What an item is used on, handed to its effect */
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Target {
+  Player,
+  Tile(i32, i32),
+  Monster(usize)
+}
+
+fn use_item(game: &mut GameState, engine: &mut EngineState, thread_ctx: &mut ThreadContext,
+            inventory_id: usize, objects: &mut Vec<Object>) -> ItemUseResult {
   use components::Item::*;
+  if game.inventory[inventory_id].equipment.is_some() {
+    return toggle_equipment(game, inventory_id);
+  }
   if let Some(item) = game.inventory[inventory_id].item {
+    let targeting = match item {
+      Heal => Targeting::Player,
+      Lightning => Targeting::ClosestMonster { range: LIGHTNING_RANGE },
+      Fireball => Targeting::Tile { range: FIREBALL_RANGE },
+      Confuse => Targeting::Monster { range: CONFUSE_RANGE },
+      Teleport | Regenerate | Haste | Poison => Targeting::Player
+    };
     let on_use = match item {
-      Heal => cast_heal
+      Heal => cast_heal,
+      Lightning => cast_lightning,
+      Fireball => cast_fireball,
+      Confuse => cast_confuse,
+      Teleport => cast_teleport,
+      Regenerate => cast_regenerate,
+      Haste => cast_haste,
+      Poison => cast_poison
+    };
+
+    let target = match targeting {
+      Targeting::Player => Some(Target::Player),
+      Targeting::Tile { range } => {
+        game.log.add(TARGETING_PROMPT, colors::LIGHT_CYAN);
+        target_tile(game, engine, objects, range).map(|(x, y)| Target::Tile(x, y))
+      }
+      Targeting::Monster { range } => {
+        game.log.add(TARGETING_PROMPT, colors::LIGHT_CYAN);
+        target_monster(game, engine, objects, range).map(Target::Monster)
+      }
+      Targeting::ClosestMonster { range } => {
+        let monster_id = closest_monster(&engine.fov, objects, range);
+        if monster_id.is_none() {
+          game.log.add("No enemy is close enough to strike.", colors::RED);
+        }
+        monster_id.map(Target::Monster)
+      }
+    };
+    let result = match target {
+      Some(target) => on_use(game, thread_ctx, objects, target),
+      None => ItemUseResult::Cancelled
     };
-    match on_use(game, objects) {
+
+    match result {
       ItemUseResult::UsedUp => {
         game.inventory.remove(inventory_id);
       }
+      ItemUseResult::UsedAndKept => {}
       ItemUseResult::Cancelled => {
         game.log.add("Cancelled", colors::WHITE);
       }
     }
+    result
   } else {
     let item_name = game.inventory[inventory_id].name.clone();
     game.log.add(format!("The {} cannot be used.", item_name), colors::WHITE);
+    ItemUseResult::Cancelled
+  }
+}
+
+fn is_targetable(x: i32, y: i32, max_range: f32, fov_map: &FovMap, player: &Object) -> bool {
+  x >= 0 && x < MAP_WIDTH && y >= 0 && y < MAP_HEIGHT &&
+    fov_map.is_in_fov(x, y) && player.distance(x, y) <= max_range
+}
+
+/* Lets the player pick a tile they can see within `max_range` of them, either by moving a
+ * cursor with the movement keys and pressing Enter or by left clicking it. Returns None when
+ * they cancel with Escape or a right click, or when a key script runs out.
+ *
+ * The cursor only follows the keyboard (not the mouse hovering) so that a recording holds
+ * everything needed to replay the choice.
+ */
+fn target_tile(game: &mut GameState, engine: &mut EngineState, objects: &[Object],
+               max_range: f32) -> Option<(i32, i32)> {
+  use tcod::input::KeyCode::*;
+
+  let camera = Camera::following(&objects[PLAYER_IDX]);
+  let (mut cursor_x, mut cursor_y) = objects[PLAYER_IDX].pos();
+  loop {
+    if !engine.is_headless() {
+      let valid = is_targetable(cursor_x, cursor_y, max_range, &engine.fov, &objects[PLAYER_IDX]);
+      render_all(game, engine, objects, false);
+      if let Some(ref mut display) = engine.display {
+        let color = if valid { colors::LIGHT_YELLOW } else { colors::RED };
+        let (screen_x, screen_y) = camera.to_screen(cursor_x, cursor_y).unwrap();
+        display.root.set_char_background(screen_x, screen_y, color, BackgroundFlag::Set);
+        display.root.flush();
+        display.root.clear();
+      }
+    }
+
+    let (dx, dy, selected) = match engine.poll_input() {
+      None => return None,
+      Some(Input::Click { right: true, .. }) => return None,
+      Some(Input::Click { x, y, right: false }) => match camera.to_map(x, y) {
+        Some((x, y)) => {
+          cursor_x = x;
+          cursor_y = y;
+          (0, 0, true)
+        }
+        None => (0, 0, false)
+      },
+      Some(Input::Key(key)) => match key.code {
+        Escape => return None,
+        Enter => (0, 0, true),
+        _ => match engine.keymap.action_for(&key).and_then(|action| action.direction()) {
+          Some((dx, dy)) => (dx, dy, false),
+          None => (0, 0, false)
+        }
+      }
+    };
+    // The cursor stays on screen
+    if camera.to_screen(cursor_x + dx, cursor_y + dy).is_some() {
+      cursor_x += dx;
+      cursor_y += dy;
+    }
+
+    if selected {
+      if is_targetable(cursor_x, cursor_y, max_range, &engine.fov, &objects[PLAYER_IDX]) {
+        return Some((cursor_x, cursor_y));
+      }
+      game.log.add("You can't target that. It has to be in view and in range.", colors::RED);
+    }
+  }
+}
+
+/* Like `target_tile`, but only accepts a tile with a living monster on it */
+fn target_monster(game: &mut GameState, engine: &mut EngineState, objects: &[Object],
+                  max_range: f32) -> Option<usize> {
+  loop {
+    let (x, y) = match target_tile(game, engine, objects, max_range) {
+      Some(pos) => pos,
+      None => return None
+    };
+    let monster_id = (0..objects.len()).find(|&id| {
+      id != PLAYER_IDX && objects[id].alive && objects[id].char_attributes.is_some() &&
+        objects[id].pos() == (x, y)
+    });
+    match monster_id {
+      Some(id) => return Some(id),
+      None => game.log.add("There's no monster there.", colors::RED)
+    }
   }
 }
 
-fn cast_heal(game: &mut GameState, objects: &mut [Object]) -> ItemUseResult {
+/* The living monster in view and within `max_range` that's closest to the player */
+fn closest_monster(fov_map: &FovMap, objects: &[Object], max_range: f32) -> Option<usize> {
+  let mut closest = None;
+  let mut closest_distance = max_range;
+  for id in 0..objects.len() {
+    let obj = &objects[id];
+    if id != PLAYER_IDX && obj.alive && obj.char_attributes.is_some() &&
+       fov_map.is_in_fov(obj.x, obj.y) {
+      let distance = objects[PLAYER_IDX].distance_to(obj);
+      if distance <= closest_distance {
+        closest = Some(id);
+        closest_distance = distance;
+      }
+    }
+  }
+  closest
+}
+
+/* Damage from something the player cast, so they get the XP for any kill */
+fn deal_spell_damage(game: &mut GameState, objects: &mut [Object], target_id: usize, damage: i32) {
+  if let Some(xp) = objects[target_id].take_damage(game, damage) {
+    if target_id != PLAYER_IDX {
+      if let Some(ref mut char_attributes) = objects[PLAYER_IDX].char_attributes {
+        char_attributes.xp += xp;
+      }
+    }
+  }
+}
+
+fn cast_heal(game: &mut GameState, _thread_ctx: &mut ThreadContext, objects: &mut [Object],
+             _target: Target) -> ItemUseResult {
   if let Some(char_attributes) = objects[PLAYER_IDX].char_attributes {
     if char_attributes.hp == char_attributes.max_hp {
       game.log.add("You're already at full health.", colors::RED);
@@ -463,81 +1109,253 @@ fn cast_heal(game: &mut GameState, objects: &mut [Object]) -> ItemUseResult {
   return ItemUseResult::Cancelled;
 }
 
-fn npc_name(label: &str, objects: &[Object]) -> String {
-  let s = format!("{}_{:}", label, objects.len() + 1);
-  return s;
+fn cast_lightning(game: &mut GameState, _thread_ctx: &mut ThreadContext, objects: &mut [Object],
+                  target: Target) -> ItemUseResult {
+  if let Target::Monster(monster_id) = target {
+    game.log.add(format!("A lightning bolt strikes {} with a loud thunder! The damage is {} hit points.",
+                         objects[monster_id].name, LIGHTNING_DAMAGE),
+                 colors::LIGHT_BLUE);
+    deal_spell_damage(game, objects, monster_id, LIGHTNING_DAMAGE);
+    return ItemUseResult::UsedUp;
+  }
+  return ItemUseResult::Cancelled;
 }
 
-fn place_objects(thread_ctx: &mut ThreadContext, room: Rect, map: &Map,
-                 objects: &mut Vec<Object>) {
-  let num_monsters = thread_ctx.rand.gen_range(0, MAX_ROOM_MONSTERS + 1);
-
-  for _ in 0..num_monsters {
-    // @incomplete if we can't place here then try again N times
-    let x = thread_ctx.rand.gen_range(room.x1 + 1, room.x2);
-    let y = thread_ctx.rand.gen_range(room.y1 + 1, room.y2);
-
-    let coll_info = check_tile_for_collision(x, y, map, objects);
-    if !coll_info.collision {
-      let roll = thread_ctx.rand.next_f32();
-      let mut monster = if roll < 0.4 {
-        // Create a witch
-        let name = npc_name("Witch", objects);
-        let mut witch = Object::new(x, y, 'W', DEFAULT_DEATH_CHAR, &name, colors::GREEN, true, true);
-        witch.char_attributes = Some(components::CharacterAttributes {
-          max_hp: 13, hp: 10, defense: 4, power: 3
-        });
-        witch.brain = Some(components::Ai);
-        witch
-      } else if roll < 0.7 {
-        // Lizard
-        let name = npc_name("Lizard", objects);
-        let mut lizard = Object::new(x, y, 'L', DEFAULT_DEATH_CHAR, &name, colors::DARKER_GREEN, true, true);
-        lizard.char_attributes = Some(components::CharacterAttributes {
-          max_hp: 7, hp: 5, defense: 2, power: 1
-        });
-        lizard.brain = Some(components::Ai);
-        lizard
-      } else {
-        // Wizard
-        let name = npc_name("Wizard", objects);
-        let mut wizard = Object::new(x, y, '@', DEFAULT_DEATH_CHAR, &name, colors::RED, true, true);
-        wizard.char_attributes = Some(components::CharacterAttributes {
-          max_hp: 16, hp: 12, defense: 3, power: 4
-        });
-        wizard.brain = Some(components::Ai);
-        wizard
-      };
-
-      monster.alive = true;
-      objects.push(monster);
+/* Burns everything around the target tile, the player included */
+fn cast_fireball(game: &mut GameState, _thread_ctx: &mut ThreadContext, objects: &mut [Object],
+                 target: Target) -> ItemUseResult {
+  if let Target::Tile(x, y) = target {
+    game.log.add(format!("The fireball explodes, burning everything within {} tiles!", FIREBALL_RADIUS),
+                 colors::ORANGE);
+    for id in 0..objects.len() {
+      if objects[id].alive && objects[id].char_attributes.is_some() &&
+         objects[id].distance(x, y) <= FIREBALL_RADIUS {
+        game.log.add(format!("{} gets burned for {} hit points.", objects[id].name, FIREBALL_DAMAGE),
+                     colors::ORANGE);
+        deal_spell_damage(game, objects, id, FIREBALL_DAMAGE);
+      }
     }
+    return ItemUseResult::UsedUp;
   }
+  return ItemUseResult::Cancelled;
+}
 
-  let num_items = thread_ctx.rand.gen_range(0, MAX_ROOM_ITEMS + 1);
-  for _ in 0..num_items {
-    // @incomplete if we can't place here then try again N times
-    let x = thread_ctx.rand.gen_range(room.x1 + 1, room.x2);
-    let y = thread_ctx.rand.gen_range(room.y1 + 1, room.y2);
+fn cast_confuse(game: &mut GameState, _thread_ctx: &mut ThreadContext, objects: &mut [Object],
+                target: Target) -> ItemUseResult {
+  if let Target::Monster(monster_id) = target {
+    if objects[monster_id].brain.is_none() {
+      return ItemUseResult::Cancelled;
+    }
+    objects[monster_id].add_status(components::StatusEffect {
+      kind: components::StatusKind::Confusion,
+      turns_left: CONFUSE_NUM_TURNS,
+      magnitude: 0
+    });
+    game.log.add(format!("The eyes of {} look vacant, as it starts to stumble around!",
+                         objects[monster_id].name),
+                 colors::LIGHT_GREEN);
+    return ItemUseResult::UsedUp;
+  }
+  return ItemUseResult::Cancelled;
+}
 
-    let coll_info = check_tile_for_collision(x, y, map, objects);
-    if !coll_info.collision {
-      let mut obj = Object::new(x, y, '!', ' ', "Healing Potion", colors::VIOLET, false, false);
-      obj.alive = true;
-      obj.item = Some(components::Item::Heal);
-      objects.push(obj);
+/* Moves the player to a random passable tile that nothing is standing on */
+fn cast_teleport(game: &mut GameState, thread_ctx: &mut ThreadContext, objects: &mut [Object],
+                 _target: Target) -> ItemUseResult {
+  let player_pos = objects[PLAYER_IDX].pos();
+  let mut destinations = vec![];
+  for y in 0..MAP_HEIGHT {
+    for x in 0..MAP_WIDTH {
+      if (x, y) != player_pos && !check_tile_for_collision(x, y, &game.map, objects).collision {
+        destinations.push((x, y));
+      }
     }
   }
+
+  if destinations.is_empty() {
+    game.log.add("The scroll fizzles. There's nowhere to go.", colors::RED);
+    return ItemUseResult::Cancelled;
+  }
+  let (x, y) = destinations[thread_ctx.rand.gen_range(0, destinations.len() as u32) as usize];
+  objects[PLAYER_IDX].set_pos(x, y);
+  game.log.add("You feel a wrenching sensation and find yourself somewhere else.",
+               colors::LIGHT_VIOLET);
+  return ItemUseResult::UsedUp;
 }
 
-fn on_object_death(obj: &mut Object, game: &mut GameState) {
+fn cast_regenerate(game: &mut GameState, _thread_ctx: &mut ThreadContext, objects: &mut [Object],
+                   _target: Target) -> ItemUseResult {
+  objects[PLAYER_IDX].add_status(components::StatusEffect {
+    kind: components::StatusKind::Regeneration,
+    turns_left: REGENERATE_NUM_TURNS,
+    magnitude: REGENERATE_AMOUNT
+  });
+  game.log.add("A warm glow spreads through you as your wounds start to close.", colors::LIGHT_VIOLET);
+  return ItemUseResult::UsedUp;
+}
+
+fn cast_haste(game: &mut GameState, _thread_ctx: &mut ThreadContext, objects: &mut [Object],
+              _target: Target) -> ItemUseResult {
+  objects[PLAYER_IDX].add_status(components::StatusEffect {
+    kind: components::StatusKind::Haste,
+    turns_left: HASTE_NUM_TURNS,
+    magnitude: HASTE_SPEED_BONUS
+  });
+  game.log.add("Everything around you seems to slow down.", colors::LIGHT_VIOLET);
+  return ItemUseResult::UsedUp;
+}
+
+/* The cursed potion: it looks like any other until it's drunk */
+fn cast_poison(game: &mut GameState, _thread_ctx: &mut ThreadContext, objects: &mut [Object],
+               _target: Target) -> ItemUseResult {
+  objects[PLAYER_IDX].add_status(components::StatusEffect {
+    kind: components::StatusKind::Poison,
+    turns_left: POISON_NUM_TURNS,
+    magnitude: POISON_DAMAGE
+  });
+  game.log.add("The potion tastes foul. You've been poisoned!", colors::LIGHT_GREEN);
+  return ItemUseResult::UsedUp;
+}
+
+fn npc_name(label: &str, objects: &[Object]) -> String {
+  let s = format!("{}_{:}", label, objects.len() + 1);
+  return s;
+}
+
+/* Fills `room` with a random number of monsters and items. Returns how many of them couldn't
+ * be placed because no free tile was found for them.
+ */
+fn place_objects(content: &Content, depth: i32, thread_ctx: &mut ThreadContext, room: Rect,
+                 map: &Map, objects: &mut Vec<Object>) -> u32 {
+  let mut num_failed = 0;
+
+  let max_monsters = content::value_at_depth(MAX_ROOM_MONSTERS, depth).unwrap_or(0);
+  let num_monsters = thread_ctx.rand.gen_range(0, max_monsters + 1);
+
+  for _ in 0..num_monsters {
+    let spawn_tile = find_spawn_tile(thread_ctx, room, map, objects, MIN_MONSTER_SPAWN_DISTANCE);
+    if let Some((x, y)) = spawn_tile {
+      let template = match thread_ctx.pick_weighted(&content.monsters, |t| t.spawn_weight(depth)) {
+        Some(template) => template,
+        None => break
+      };
+
+      let name = npc_name(&template.name, objects);
+      let mut monster = Object::new(x, y, template.glyph, DEFAULT_DEATH_CHAR, &name, template.color,
+                                    true, true);
+      monster.char_attributes = Some(template.attributes);
+      monster.brain = Some(match template.ai {
+        AiKind::Basic => components::Ai::new(template.sight_radius)
+      });
+      monster.alive = true;
+      objects.push(monster);
+    } else {
+      num_failed += 1;
+    }
+  }
+
+  let max_items = content::value_at_depth(MAX_ROOM_ITEMS, depth).unwrap_or(0);
+  let num_items = thread_ctx.rand.gen_range(0, max_items + 1);
+
+  for _ in 0..num_items {
+    if let Some((x, y)) = find_spawn_tile(thread_ctx, room, map, objects, 0.0) {
+      let template = match thread_ctx.pick_weighted(&content.items, |t| t.spawn_weight(depth)) {
+        Some(template) => template,
+        None => break
+      };
+
+      let mut obj = Object::new(x, y, template.glyph, ' ', &template.name, template.color,
+                                false, false);
+      obj.alive = true;
+      obj.item = template.effect;
+      obj.equipment = template.equipment;
+      objects.push(obj);
+    } else {
+      num_failed += 1;
+    }
+  }
+
+  num_failed
+}
+
+/* Picks a random open tile in `room` with nothing on it (the player included) that's at least
+ * `min_player_distance` away from the player. Gives up after MAX_SPAWN_TRIES tiles.
+ */
+fn find_spawn_tile(thread_ctx: &mut ThreadContext, room: Rect, map: &Map, objects: &[Object],
+                   min_player_distance: f32) -> Option<(i32, i32)> {
+  for _ in 0..MAX_SPAWN_TRIES {
+    let x = thread_ctx.rand.gen_range(room.x1 + 1, room.x2);
+    let y = thread_ctx.rand.gen_range(room.y1 + 1, room.y2);
+
+    let is_free = map[(y * MAP_WIDTH + x) as usize].passable &&
+      !objects.iter().any(|obj| obj.pos() == (x, y));
+    if is_free && objects[PLAYER_IDX].distance(x, y) >= min_player_distance {
+      return Some((x, y));
+    }
+  }
+  None
+}
+
+fn xp_to_level_up(level: i32) -> i32 {
+  LEVEL_UP_BASE + level * LEVEL_UP_FACTOR
+}
+
+/* Levels up the player for every threshold their XP has crossed, asking which stat to raise
+ * each time. Closing the window leaves the rest for when the game is next loaded.
+ */
+fn level_up(game: &mut GameState, engine: &mut EngineState, objects: &mut [Object]) {
+  while let Some(char_attributes) = objects[PLAYER_IDX].char_attributes {
+    let xp_needed = xp_to_level_up(char_attributes.level);
+    if char_attributes.xp < xp_needed {
+      break;
+    }
+
+    let new_level = char_attributes.level + 1;
+    game.log.add(format!("Your battle skills grow stronger! You reached level {}!", new_level),
+                 colors::YELLOW);
+
+    let options = [
+      format!("Constitution (+20 HP, from {})", char_attributes.max_hp),
+      format!("Strength (+1 attack, from {})", char_attributes.power),
+      format!("Agility (+1 defense, from {})", char_attributes.defense),
+    ];
+    let mut choice = None;
+    while choice.is_none() {
+      choice = render_menu("Level up! Choose a stat to raise:\n", &options, LEVEL_SCREEN_WIDTH,
+                           engine, "");
+      // A key script that ran out can't pick, so take the first option rather than hang
+      if choice.is_none() && engine.is_out_of_input() {
+        choice = Some(0);
+      }
+      if choice.is_none() && engine.is_window_closed() {
+        return;
+      }
+    }
+
+    if let Some(ref mut char_attributes) = objects[PLAYER_IDX].char_attributes {
+      char_attributes.level = new_level;
+      char_attributes.xp -= xp_needed;
+      match choice.unwrap() {
+        0 => {
+          char_attributes.max_hp += 20;
+          char_attributes.hp += 20;
+        }
+        1 => char_attributes.power += 1,
+        _ => char_attributes.defense += 1,
+      }
+    }
+  }
+}
+
+fn on_object_death(obj: &mut Object, game: &mut GameState) {
   match obj.brain {
-    Some(brain) => {
+    Some(_) => {
       // AI
       game.log.add(format!("{} died!", obj.name), colors::RED);
       obj.name = format!("{} [corpse]", obj.name);
       obj.blocks = false;
       obj.brain = None;
+      obj.path.clear();
     },
     // player
     None => {
@@ -545,6 +1363,7 @@ fn on_object_death(obj: &mut Object, game: &mut GameState) {
       obj.blocks = false;
     }
   }
+  obj.status_effects.clear();
 }
 
 fn attempt_move(id: usize, dx: i32, dy: i32, map: &Map, objects: &mut [Object]) -> TileCollisionInfo {
@@ -559,7 +1378,62 @@ fn attempt_move(id: usize, dx: i32, dy: i32, map: &Map, objects: &mut [Object])
   return coll_info;
 }
 
-fn move_towards(id: usize, (target_x, target_y): (i32, i32), map: &Map, objects: &mut [Object]) {
+/* Follows an A* path to the target. The path is cached on the object and only searched for
+ * again once it's used up, blocked, or no longer ends next to the target. When there's no
+ * path at all we fall back to stepping straight at the target.
+ */
+fn move_towards(id: usize, target: (i32, i32), map: &Map, objects: &mut [Object]) {
+  if !is_path_usable(id, target, map, objects) {
+    objects[id].path = find_path(id, target, map, objects).unwrap_or(vec![]);
+  }
+
+  if !objects[id].path.is_empty() {
+    let (x, y) = objects[id].path.remove(0);
+    let (dx, dy) = (x - objects[id].x, y - objects[id].y);
+    if !attempt_move(id, dx, dy, map, objects).collision {
+      return;
+    }
+    // Still blocked right after searching, so someone is in the way of the only good route
+    objects[id].path.clear();
+  }
+
+  move_straight_towards(id, target, map, objects);
+}
+
+fn is_path_usable(id: usize, (target_x, target_y): (i32, i32), map: &Map, objects: &[Object]) -> bool {
+  let path = &objects[id].path;
+  let (next_x, next_y) = match path.first() {
+    Some(&step) => step,
+    None => return false
+  };
+  let (end_x, end_y) = path[path.len() - 1];
+  let next_is_adjacent = (next_x - objects[id].x).abs() <= 1 && (next_y - objects[id].y).abs() <= 1;
+  let ends_at_target = (end_x - target_x).abs() <= 1 && (end_y - target_y).abs() <= 1;
+  next_is_adjacent && ends_at_target && !check_tile_for_collision(next_x, next_y, map, objects).collision
+}
+
+fn find_path(id: usize, target: (i32, i32), map: &Map, objects: &[Object]) -> Option<Vec<(i32, i32)>> {
+  let mut occupied = vec![false; map.len()];
+  for (other_id, obj) in objects.iter().enumerate() {
+    if other_id != id && obj.blocks {
+      occupied[(obj.y * MAP_WIDTH + obj.x) as usize] = true;
+    }
+  }
+
+  pathfinding::find_path(MAP_WIDTH, MAP_HEIGHT, objects[id].pos(), target, |x, y| {
+    let idx = (y * MAP_WIDTH + x) as usize;
+    if !map[idx].passable {
+      None
+    } else if occupied[idx] {
+      Some(PATH_OCCUPIED_TILE_COST)
+    } else {
+      Some(0)
+    }
+  })
+}
+
+fn move_straight_towards(id: usize, (target_x, target_y): (i32, i32), map: &Map,
+                         objects: &mut [Object]) {
   let dx = target_x - objects[id].x;
   let dy = target_y - objects[id].y;
   let distance = ((dx.pow(2) + dy.pow(2)) as f32).sqrt();
@@ -569,17 +1443,28 @@ fn move_towards(id: usize, (target_x, target_y): (i32, i32), map: &Map, objects:
   attempt_move(id, dx, dy, map, objects);
 }
 
-fn player_move_or_attack(game: &mut GameState, dx: i32, dy: i32, objects: &mut [Object]) {
+fn player_move_or_attack(game: &mut GameState, thread_ctx: &mut ThreadContext, dx: i32, dy: i32,
+                         objects: &mut [Object]) {
+  let (dx, dy) = if objects[PLAYER_IDX].has_status(components::StatusKind::Confusion) {
+    (thread_ctx.rand.gen_range(-1, 2), thread_ctx.rand.gen_range(-1, 2))
+  } else {
+    (dx, dy)
+  };
   let coll_info = attempt_move(PLAYER_IDX, dx, dy, &game.map, objects);
-  if coll_info.obj_collision && coll_info.collision_id.is_some() {
+  let mut cost = MOVE_COST;
+  // A confused stumble can land back on the player's own tile
+  if coll_info.obj_collision && coll_info.collision_id.is_some() &&
+     coll_info.collision_id != Some(PLAYER_IDX) {
     let (player, target) = mut_two(PLAYER_IDX, coll_info.collision_id.unwrap(), objects);
     if target.alive {
-      player.attack(target, game);
+      player.attack(target, game, thread_ctx);
+      cost = ATTACK_COST;
     }
     else {
       game.log.add(format!("{} chops at the corpse of {}. Blood sprays out.", player.name, target.name), colors::BLUE);
     }
   }
+  objects[PLAYER_IDX].spend_energy(cost);
 }
 
 fn visible_objects_at_pos<'a, 'b>(x: i32, y: i32, objects: &'a [Object], fov_map: &'b FovMap) -> Vec<&'a Object> {
@@ -592,73 +1477,459 @@ fn visible_objects_at_pos<'a, 'b>(x: i32, y: i32, objects: &'a [Object], fov_map
   return ret;
 }
 
-fn ai_take_turn(game: &mut GameState, engine: &mut EngineState, npc_id: usize,
+fn ai_take_turn(game: &mut GameState, thread_ctx: &mut ThreadContext, npc_id: usize,
                 objects: &mut [Object]) {
-  let (npc_x, npc_y) = objects[npc_id].pos();
+  let mut ai = match objects[npc_id].brain.take() {
+    Some(ai) => ai,
+    None => return
+  };
+
+  let cost = if objects[npc_id].has_status(components::StatusKind::Confusion) {
+    move_randomly(thread_ctx, npc_id, &game.map, objects);
+    MOVE_COST
+  } else {
+    ai_act(game, thread_ctx, npc_id, objects, &mut ai)
+  };
 
-  if engine.fov.is_in_fov(npc_x, npc_y) {
+  objects[npc_id].spend_energy(cost);
+  objects[npc_id].brain = Some(ai);
+}
+
+/* Returns the energy cost of what the monster did */
+fn ai_act(game: &mut GameState, thread_ctx: &mut ThreadContext, npc_id: usize,
+          objects: &mut [Object], ai: &mut components::Ai) -> i32 {
+  use components::AiMode::*;
+  let sees_player = objects[PLAYER_IDX].alive && can_see(&objects[npc_id], ai.sight_radius,
+                                                        objects[PLAYER_IDX].pos(), &game.map);
+  if sees_player {
+    ai.last_seen_player = Some(objects[PLAYER_IDX].pos());
+    ai.chase_turns_left = CHASE_TURNS;
+    ai.mode = if is_badly_hurt(&objects[npc_id]) { Flee } else { Hunt };
+  }
+
+  match ai.mode {
+    Idle => MOVE_COST,
+    Wander => {
+      move_randomly(thread_ctx, npc_id, &game.map, objects);
+      MOVE_COST
+    }
+    Hunt => ai_hunt(game, thread_ctx, npc_id, objects, ai, sees_player),
+    Flee => ai_flee(game, thread_ctx, npc_id, objects, ai, sees_player)
+  }
+}
+
+/* Whether `viewer` can see the tile at `target`: it has to be within `sight_radius` and
+ * every tile on the line between them has to be see-through.
+ */
+fn can_see(viewer: &Object, sight_radius: i32, target: (i32, i32), map: &Map) -> bool {
+  if viewer.distance(target.0, target.1) > sight_radius as f32 {
+    return false;
+  }
+  line_between(viewer.pos(), target).iter()
+    .all(|&(x, y)| !map[(y * MAP_WIDTH + x) as usize].blocks_sight)
+}
+
+/* The tiles on a Bresenham line from `from` to `to`, not counting either end */
+fn line_between(from: (i32, i32), to: (i32, i32)) -> Vec<(i32, i32)> {
+  let (mut x, mut y) = from;
+  let dx = (to.0 - x).abs();
+  let dy = -(to.1 - y).abs();
+  let step_x = if x < to.0 { 1 } else { -1 };
+  let step_y = if y < to.1 { 1 } else { -1 };
+  let mut error = dx + dy;
+
+  let mut tiles = vec![];
+  while (x, y) != to {
+    let doubled_error = 2 * error;
+    if doubled_error >= dy {
+      error += dy;
+      x += step_x;
+    }
+    if doubled_error <= dx {
+      error += dx;
+      y += step_y;
+    }
+    if (x, y) != to {
+      tiles.push((x, y));
+    }
+  }
+  tiles
+}
+
+fn is_badly_hurt(obj: &Object) -> bool {
+  obj.char_attributes.map_or(false, |c| c.hp * 100 <= c.max_hp * FLEE_HP_PERCENT)
+}
+
+/* Attacks the player when they're in view, otherwise walks to where they were last seen and
+ * searches around there until the chase times out.
+ */
+fn ai_hunt(game: &mut GameState, thread_ctx: &mut ThreadContext, npc_id: usize,
+           objects: &mut [Object], ai: &mut components::Ai, sees_player: bool) -> i32 {
+  if sees_player {
     if objects[npc_id].distance_to(&objects[PLAYER_IDX]) >= 2.0 {
       let player_pos = objects[PLAYER_IDX].pos();
       move_towards(npc_id, player_pos, &game.map, objects);
+      return MOVE_COST;
     }
-    else if objects[PLAYER_IDX].alive {
+    else {
       let (npc, player) = mut_two(npc_id, PLAYER_IDX, objects);
-      npc.attack(player, game);
+      npc.attack(player, game, thread_ctx);
+      return ATTACK_COST;
+    }
+  }
+
+  ai.chase_turns_left -= 1;
+  if ai.chase_turns_left <= 0 {
+    ai.mode = components::AiMode::Wander;
+    ai.last_seen_player = None;
+    return MOVE_COST;
+  }
+
+  match ai.last_seen_player {
+    Some(spot) if spot != objects[npc_id].pos() => move_towards(npc_id, spot, &game.map, objects),
+    _ => {
+      // Reached the spot and the player isn't here, so look around
+      ai.last_seen_player = None;
+      move_randomly(thread_ctx, npc_id, &game.map, objects);
+    }
+  }
+  MOVE_COST
+}
+
+/* Steps to whichever free neighboring tile is furthest from the player. A cornered monster
+ * fights back.
+ */
+fn ai_flee(game: &mut GameState, thread_ctx: &mut ThreadContext, npc_id: usize,
+           objects: &mut [Object], ai: &mut components::Ai, sees_player: bool) -> i32 {
+  if !sees_player {
+    ai.mode = components::AiMode::Wander;
+    ai.last_seen_player = None;
+    return MOVE_COST;
+  }
+
+  let (npc_x, npc_y) = objects[npc_id].pos();
+  let mut best_step = None;
+  let mut best_distance = objects[npc_id].distance_to(&objects[PLAYER_IDX]);
+  for dy in -1..2 {
+    for dx in -1..2 {
+      let distance = objects[PLAYER_IDX].distance(npc_x + dx, npc_y + dy);
+      if distance > best_distance &&
+         !check_tile_for_collision(npc_x + dx, npc_y + dy, &game.map, objects).collision {
+        best_step = Some((dx, dy));
+        best_distance = distance;
+      }
+    }
+  }
+
+  match best_step {
+    Some((dx, dy)) => {
+      attempt_move(npc_id, dx, dy, &game.map, objects);
+      MOVE_COST
+    }
+    None if objects[npc_id].distance_to(&objects[PLAYER_IDX]) < 2.0 => {
+      let (npc, player) = mut_two(npc_id, PLAYER_IDX, objects);
+      npc.attack(player, game, thread_ctx);
+      ATTACK_COST
+    }
+    None => MOVE_COST
+  }
+}
+
+/* Runs game time forward until the player has the energy to act again. Every tick each
+ * creature gains energy equal to its speed, and each monster that has enough takes a turn.
+ * Monsters act in object order, so a given seed always plays out the same way.
+ */
+fn run_until_player_ready(game: &mut GameState, thread_ctx: &mut ThreadContext,
+                          objects: &mut [Object]) {
+  loop {
+    while objects[PLAYER_IDX].alive && !objects[PLAYER_IDX].has_energy_to_act() {
+      for obj in objects.iter_mut() {
+        if obj.alive {
+          let speed = obj.speed();
+          if let Some(ref mut char_attributes) = obj.char_attributes {
+            char_attributes.energy += speed;
+          }
+        }
+      }
+      for id in 0..objects.len() {
+        if id != PLAYER_IDX && objects[id].brain.is_some() && objects[id].alive &&
+           objects[id].has_energy_to_act() {
+          if start_turn(game, id, objects) {
+            ai_take_turn(game, thread_ctx, id, objects);
+          } else {
+            objects[id].spend_energy(MOVE_COST);
+          }
+        }
+      }
+    }
+
+    if !objects[PLAYER_IDX].alive || start_turn(game, PLAYER_IDX, objects) {
+      break;
+    }
+    objects[PLAYER_IDX].spend_energy(MOVE_COST);
+  }
+}
+
+/* Ticks an object's status effects at the start of its turn and counts them down. Returns
+ * false when it loses the turn, either to a stun or by dying.
+ */
+fn start_turn(game: &mut GameState, id: usize, objects: &mut [Object]) -> bool {
+  use components::StatusKind::*;
+  let effects = objects[id].status_effects.clone();
+  for effect in &effects {
+    match effect.kind {
+      Poison => {
+        if objects[id].is_player {
+          game.log.add(format!("The poison burns for {} damage.", effect.magnitude), colors::LIGHT_GREEN);
+        }
+        // Nobody gets XP for a poisoning
+        objects[id].take_damage(game, effect.magnitude);
+      }
+      Regeneration => objects[id].heal(effect.magnitude),
+      Confusion | Stun | Haste => {}
+    }
+  }
+  if !objects[id].alive {
+    return false;
+  }
+
+  let stunned = objects[id].has_status(Stun);
+  for effect in objects[id].status_effects.iter_mut() {
+    effect.turns_left -= 1;
+  }
+  // Only mention monsters the player can see
+  let (x, y) = objects[id].pos();
+  if objects[id].is_player || game.map[(y * MAP_WIDTH + x) as usize].visible {
+    for effect in objects[id].status_effects.iter().filter(|effect| effect.turns_left <= 0) {
+      game.log.add(format!("{} is no longer {}.", objects[id].name, effect.kind.adjective()),
+                   colors::LIGHT_GREY);
+    }
+  }
+  objects[id].status_effects.retain(|effect| effect.turns_left > 0);
+  !stunned
+}
+
+/* Where the player is walking to on their own */
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Travel {
+  Explore, // Towards the nearest unexplored tile, for as long as there is one
+  To(i32, i32)
+}
+
+/* Walks the player one step at a time, letting the monsters act between steps, until they
+ * get where they were going, a monster comes into view or they get hurt. Exploring also stops
+ * on items. Returns how many turns it took.
+ */
+fn travel(game: &mut GameState, engine: &mut EngineState, thread_ctx: &mut ThreadContext,
+          objects: &mut [Object], target: Travel) -> u32 {
+  if objects[PLAYER_IDX].has_status(components::StatusKind::Confusion) {
+    game.log.add("You're too confused to find your way around.", colors::RED);
+    return 0;
+  }
+
+  // The FOV is already current, but on the first step of a game the map hasn't caught up yet
+  update_map(game, &mut engine.fov, true);
+
+  let mut monsters_in_view = visible_monsters(&engine.fov, objects);
+  let mut num_turns = 0;
+  loop {
+    let path = match travel_path(game, &engine.fov, objects, target, true) {
+      Some(ref path) if path.is_empty() => break, // Already there
+      Some(path) => path,
+      None if travel_path(game, &engine.fov, objects, target, false).is_some() => {
+        game.log.add("There's a monster in the way.", colors::LIGHT_GREY);
+        break;
+      }
+      None if target == Travel::Explore => {
+        game.log.add("There's nothing left to explore here.", colors::LIGHT_GREY);
+        break;
+      }
+      None => {
+        game.log.add("You don't know a way there.", colors::LIGHT_GREY);
+        break;
+      }
+    };
+
+    if !engine.is_headless() {
+      if let Some(ref mut display) = engine.display {
+        display.planned_path = path.clone();
+      }
+      render_all(game, engine, objects, true);
+      if let Some(ref mut display) = engine.display {
+        display.root.flush();
+        display.root.clear();
+        for object in objects.iter() {
+          object.clear(&mut display.con);
+        }
+      }
+    }
+
+    let hp_before = objects[PLAYER_IDX].char_attributes.map_or(0, |x| x.hp);
+    let (player_x, player_y) = objects[PLAYER_IDX].pos();
+    let (step_x, step_y) = path[0];
+    attempt_move(PLAYER_IDX, step_x - player_x, step_y - player_y, &game.map, objects);
+    if objects[PLAYER_IDX].pos() == (player_x, player_y) {
+      // Something out of view is standing in the way
+      game.log.add("Something blocks your way.", colors::LIGHT_GREY);
+      break;
+    }
+    objects[PLAYER_IDX].spend_energy(MOVE_COST);
+    num_turns += 1;
+
+    run_until_player_ready(game, thread_ctx, objects);
+    level_up(game, engine, objects);
+    let (player_x, player_y) = objects[PLAYER_IDX].pos();
+    engine.fov.compute_fov(player_x, player_y, TORCH_RADIUS, FOV_LIGHT_WALLS, FOV_ALGO);
+    update_map(game, &mut engine.fov, true);
+
+    if !objects[PLAYER_IDX].alive || engine.is_window_closed() {
+      break;
+    }
+    if objects[PLAYER_IDX].char_attributes.map_or(0, |x| x.hp) < hp_before {
+      game.log.add("You stop.", colors::LIGHT_GREY);
+      break;
+    }
+    let now_in_view = visible_monsters(&engine.fov, objects);
+    if let Some(&id) = now_in_view.iter().find(|id| !monsters_in_view.contains(id)) {
+      game.log.add(format!("You spot {}.", objects[id].name), colors::LIGHT_GREY);
+      break;
+    }
+    monsters_in_view = now_in_view;
+    if target == Travel::Explore {
+      let item_here = objects.iter()
+        .find(|obj| obj.can_be_picked_up() && obj.pos() == (player_x, player_y));
+      if let Some(item) = item_here {
+        game.log.add(format!("You see a {} here.", item.name), colors::LIGHT_GREY);
+        break;
+      }
     }
   }
+
+  if let Some(ref mut display) = engine.display {
+    display.planned_path.clear();
+  }
+  num_turns
+}
+
+/* The way to where the player is travelling, only going through explored tiles and, if
+ * `avoid_monsters` is set, around any monster the player can see.
+ */
+fn travel_path(game: &GameState, fov_map: &FovMap, objects: &[Object], target: Travel,
+               avoid_monsters: bool) -> Option<Vec<(i32, i32)>> {
+  let tile_cost = |x: i32, y: i32| {
+    let seen_blocker = avoid_monsters && objects.iter().any(|obj| {
+      obj.blocks && !obj.is_player && obj.pos() == (x, y) && fov_map.is_in_fov(x, y)
+    });
+    let tile = &game.map[(y * MAP_WIDTH + x) as usize];
+    if tile.passable && tile.explored && !seen_blocker {
+      Some(0)
+    } else {
+      None
+    }
+  };
+
+  let start = objects[PLAYER_IDX].pos();
+  match target {
+    Travel::Explore => {
+      pathfinding::find_path_to_nearest(MAP_WIDTH, MAP_HEIGHT, start,
+                                        |x, y| !game.map[(y * MAP_WIDTH + x) as usize].explored,
+                                        tile_cost)
+    }
+    Travel::To(x, y) => pathfinding::find_path(MAP_WIDTH, MAP_HEIGHT, start, (x, y), tile_cost)
+  }
+}
+
+fn visible_monsters(fov_map: &FovMap, objects: &[Object]) -> Vec<usize> {
+  (0..objects.len())
+    .filter(|&id| objects[id].brain.is_some() && objects[id].alive &&
+                  fov_map.is_in_fov(objects[id].x, objects[id].y))
+    .collect()
+}
+
+fn move_randomly(thread_ctx: &mut ThreadContext, id: usize, map: &Map, objects: &mut [Object]) {
+  let dx = thread_ctx.rand.gen_range(-1, 2);
+  let dy = thread_ctx.rand.gen_range(-1, 2);
+  attempt_move(id, dx, dy, map, objects);
 }
 
 fn handle_input(key: Key, game: &mut GameState, engine: &mut EngineState,
-                objects: &mut Vec<Object>) -> PlayerAction {
-  use tcod::input::KeyCode::*;
+                thread_ctx: &mut ThreadContext, objects: &mut Vec<Object>) -> PlayerAction {
   use PlayerAction::*;
 
+  let action = match engine.keymap.action_for(&key) {
+    Some(action) => action,
+    None => return DidntTakeTurn
+  };
   let is_player_alive = objects[PLAYER_IDX].alive;
-  match (key, is_player_alive) {
-    // Toggle fullscreen
-    (Key { code: Enter, alt: true, .. }, _) => {
-      let fullscreen = engine.root.is_fullscreen();
-      engine.root.set_fullscreen(!fullscreen);
+  match (action, is_player_alive) {
+    (Action::ToggleFullscreen, _) => {
+      if let Some(ref mut display) = engine.display {
+        let fullscreen = display.root.is_fullscreen();
+        display.root.set_fullscreen(!fullscreen);
+      }
       DidntTakeTurn
     }
 
-    // Exit game
-    (Key { code: Escape, .. }, _) => Exit,
+    (Action::Exit, _) => Exit,
 
-    // Movement
-    (Key { code: Up, .. }, true) => {
-      player_move_or_attack(game, 0, -1, objects);
-      TookTurn
-    }
-    (Key { code: Down, .. }, true) => {
-      player_move_or_attack(game, 0, 1, objects);
-      TookTurn
+    (Action::Help, _) => {
+      show_help(engine);
+      DidntTakeTurn
     }
-    (Key { code: Left, .. }, true) => {
-      player_move_or_attack(game, -1, 0, objects);
+
+    (action, true) if action.direction().is_some() => {
+      let (dx, dy) = action.direction().unwrap();
+      player_move_or_attack(game, thread_ctx, dx, dy, objects);
       TookTurn
     }
-    (Key { code: Right, .. }, true) => {
-      player_move_or_attack(game, 1, 0, objects);
+
+    (Action::Wait, true) => {
+      objects[PLAYER_IDX].spend_energy(MOVE_COST);
       TookTurn
     }
 
-    // Everything else
-
-    // Open inventory
-    (Key { printable: 'i', .. }, true) => {
+    (Action::Inventory, true) => {
       let inventory_idx = render_inventory_menu(game, engine);
       if let Some(inventory_idx) = inventory_idx {
-        use_item(game, inventory_idx, objects);
-        return TookTurn;
+        match use_item(game, engine, thread_ctx, inventory_idx, objects) {
+          ItemUseResult::UsedUp => {
+            objects[PLAYER_IDX].spend_energy(USE_ITEM_COST);
+            return TookTurn;
+          }
+          ItemUseResult::UsedAndKept => {
+            objects[PLAYER_IDX].spend_energy(EQUIP_COST);
+            return TookTurn;
+          }
+          ItemUseResult::Cancelled => {}
+        }
       }
       DidntTakeTurn
     }
 
-    // Pick up item
-    (Key { printable: 'g', .. }, true) => {
+    (Action::Descend, true) => {
+      let player_pos = objects[PLAYER_IDX].pos();
+      let on_stairs = objects.iter().any(|obj| obj.is_stairs && obj.pos() == player_pos);
+      if on_stairs {
+        Descend
+      } else {
+        game.log.add("There are no stairs here.", colors::WHITE);
+        DidntTakeTurn
+      }
+    }
+
+    (Action::Save, true) => SaveGame,
+
+    // Explore until something interesting happens
+    (Action::Explore, true) => {
+      match travel(game, engine, thread_ctx, objects, Travel::Explore) {
+        0 => DidntTakeTurn,
+        num_turns => TookTurns(num_turns)
+      }
+    }
+
+    (Action::PickUp, true) => {
       let item_id = objects.iter().position(|obj| {
-        obj.item.is_some() && obj.pos() == objects[PLAYER_IDX].pos()
+        obj.can_be_picked_up() && obj.pos() == objects[PLAYER_IDX].pos()
       });
       if let Some(item_id) = item_id {
         pick_up_item(game, item_id, objects);
@@ -670,6 +1941,23 @@ fn handle_input(key: Key, game: &mut GameState, engine: &mut EngineState,
   }
 }
 
+/* Left clicking an explored tile walks the player there. Clicks are in screen coordinates. */
+fn handle_click(screen_x: i32, screen_y: i32, game: &mut GameState, engine: &mut EngineState,
+                thread_ctx: &mut ThreadContext, objects: &mut Vec<Object>) -> PlayerAction {
+  let (x, y) = match Camera::following(&objects[PLAYER_IDX]).to_map(screen_x, screen_y) {
+    Some(pos) if objects[PLAYER_IDX].alive => pos,
+    _ => return PlayerAction::DidntTakeTurn
+  };
+  let tile = &game.map[(y * MAP_WIDTH + x) as usize];
+  if !tile.explored || !tile.passable {
+    return PlayerAction::DidntTakeTurn;
+  }
+  match travel(game, engine, thread_ctx, objects, Travel::To(x, y)) {
+    0 => PlayerAction::DidntTakeTurn,
+    num_turns => PlayerAction::TookTurns(num_turns)
+  }
+}
+
 fn update_map(game: &mut GameState, fov_map: &mut FovMap, player_moved: bool) {
   // For now we only care about updating tile visibility and that only needs to happen
   // when the player moved
@@ -688,7 +1976,29 @@ fn update_map(game: &mut GameState, fov_map: &mut FovMap, player_moved: bool) {
 }
 
 fn render_menu<T: AsRef<str>>(header: &str, options: &[T], width: i32,
-                              root: &mut Root, empty_message: &str) -> Option<usize> {
+                              engine: &mut EngineState, empty_message: &str) -> Option<usize> {
+  let num_opts: i32 = options.len() as i32;
+  assert!(num_opts <= 26, "Cannot have a menu with more than 26 options");
+
+  if let Some(ref mut display) = engine.display {
+    draw_menu(header, options, width, &mut display.root, empty_message);
+  }
+  let key = engine.wait_for_key();
+
+  if key.printable.is_alphabetic() {
+    let idx = key.printable.to_ascii_lowercase() as usize - 'a' as usize;
+    if idx < num_opts as usize {
+      Some(idx)
+    } else {
+      None
+    }
+  } else {
+    None
+  }
+}
+
+fn draw_menu<T: AsRef<str>>(header: &str, options: &[T], width: i32, root: &mut Root,
+                            empty_message: &str) {
   let num_opts: i32 = options.len() as i32;
   let opts_padding = if num_opts == 0 {
     2
@@ -696,8 +2006,6 @@ fn render_menu<T: AsRef<str>>(header: &str, options: &[T], width: i32,
     num_opts * 2
   };
 
-  assert!(num_opts <= 26, "Cannot have a menu with more than 26 options");
-
   let header_height = root.get_height_rect(0, 0, width, SCREEN_HEIGHT, header);
   let height = opts_padding + header_height;
   let mut window = Offscreen::new(width, height);
@@ -725,29 +2033,34 @@ fn render_menu<T: AsRef<str>>(header: &str, options: &[T], width: i32,
   let y = SCREEN_HEIGHT / 2 - height / 2;
   tcod::console::blit(&mut window, (0, 0), (width, height), root, (x, y), 1.0, 0.7);
   root.flush();
-  let key = root.wait_for_keypress(true);
+}
 
-  if key.printable.is_alphabetic() {
-    let idx = key.printable.to_ascii_lowercase() as usize - 'a' as usize;
-    if idx < num_opts as usize {
-      Some(idx)
-    } else {
-      None
-    }
-  } else {
-    None
+/* Lists the current key bindings until a key is pressed */
+fn show_help(engine: &mut EngineState) {
+  let header = format!("Keys (set in {})\n\n{}\n", keymap::KEYMAP_PATH,
+                       engine.keymap.help_lines().join("\n"));
+  let no_options: &[&str] = &[];
+  if let Some(ref mut display) = engine.display {
+    draw_menu(&header, no_options, HELP_SCREEN_WIDTH, &mut display.root, "Press any key to go back.");
   }
+  engine.wait_for_key();
 }
 
 fn render_inventory_menu(game: &mut GameState, engine: &mut EngineState) -> Option<usize> {
   let options = if game.inventory.is_empty() {
     vec![]
   } else {
-    game.inventory.iter().map(|item| { item.name.clone() }).collect()
+    game.inventory.iter().map(|item| {
+      match item.equipment {
+        Some(gear) if gear.equipped => format!("{} (on {})", item.name, gear.slot),
+        _ => item.name.clone()
+      }
+    }).collect()
   };
 
-  let header = "Use an item by pressing the key next to it.\n";
-  let inventory_idx = render_menu(header, &options, INVENTORY_WIDTH, &mut engine.root,
+  let header = "Use or equip an item by pressing the key next to it. \
+                Pick something that's equipped to take it off.\n";
+  let inventory_idx = render_menu(header, &options, INVENTORY_WIDTH, engine,
                                   "Inventory is empty!");
 
   if game.inventory.len() > 0 {
@@ -775,10 +2088,26 @@ fn render_bar(panel: &mut Offscreen, x: i32, y: i32, total_width: i32, name: &st
                  &format!("{}: {}/{}", name, value, maximum));
 }
 
+fn status_letter(kind: components::StatusKind) -> (char, Color) {
+  use components::StatusKind::*;
+  match kind {
+    Poison => ('P', colors::GREEN),
+    Regeneration => ('R', colors::LIGHT_RED),
+    Confusion => ('C', colors::LIGHT_VIOLET),
+    Stun => ('S', colors::YELLOW),
+    Haste => ('H', colors::LIGHT_CYAN)
+  }
+}
 
 // NOTE: We use the type &[Object] for objects because we want an immutable slice (a view)
 fn render_all(game: &mut GameState, engine: &mut EngineState, objects: &[Object],
               render_map: bool) {
+  let display = match engine.display {
+    Some(ref mut display) => display,
+    None => return
+  };
+  let fov = &engine.fov;
+
   // No need to re-render the map unless the FOV needs to be recomputed
   if render_map {
     for y in 0..MAP_HEIGHT {
@@ -795,46 +2124,87 @@ fn render_all(game: &mut GameState, engine: &mut EngineState, objects: &[Object]
             (true, true) => COLOR_LIGHT_WALL,
             (true, false) => COLOR_LIGHT_GROUND,
           };
-          engine.con.set_char_background(x, y, color, BackgroundFlag::Set);
+          display.con.set_char_background(x, y, color, BackgroundFlag::Set);
         }
       }
     }
+
+    for &(x, y) in &display.planned_path {
+      display.con.set_char_background(x, y, COLOR_PLANNED_PATH, BackgroundFlag::Set);
+    }
   }
 
   let mut to_draw: Vec<_> = objects
     .iter()
-    .filter(|o| game.debug_disable_fog || engine.fov.is_in_fov(o.x, o.y))
+    .filter(|o| {
+      game.debug_disable_fog || fov.is_in_fov(o.x, o.y) ||
+        (o.always_visible && game.map[(o.y * MAP_WIDTH + o.x) as usize].explored)
+    })
     .collect();
 
   to_draw.sort_by(|o1, o2| { o1.blocks.cmp(&o2.blocks) });
   for obj in &to_draw {
-    obj.draw(&mut engine.con);
+    obj.draw(&mut display.con);
   }
 
-  blit(&engine.con,
-       (0, 0), (MAP_WIDTH, MAP_HEIGHT),
-       &mut engine.root,
+  let camera = Camera::following(&objects[PLAYER_IDX]);
+  blit(&display.con,
+       (camera.x, camera.y), (VIEW_WIDTH, VIEW_HEIGHT),
+       &mut display.root,
        (0, 0), 1.0, 1.0);
 
   // Render the info panel
 
   // Show stats
-  engine.panel.set_default_background(colors::BLACK);
-  engine.panel.clear();
+  display.panel.set_default_background(colors::BLACK);
+  display.panel.clear();
 
   let hp = objects[PLAYER_IDX].char_attributes.map_or(0, |f| f.hp);
   let max_hp = objects[PLAYER_IDX].char_attributes.map_or(0, |f| f.max_hp);
-  render_bar(&mut engine.panel, 1, 1, BAR_WIDTH, "HP", hp, max_hp,
+  render_bar(&mut display.panel, 1, 1, BAR_WIDTH, "HP", hp, max_hp,
              colors::WHITE, colors::LIGHT_RED, colors::DARKER_RED);
 
+  for (idx, effect) in objects[PLAYER_IDX].status_effects.iter().enumerate() {
+    let (letter, color) = status_letter(effect.kind);
+    display.panel.set_default_foreground(color);
+    display.panel.put_char(STATUS_X + idx as i32, 1, letter, BackgroundFlag::None);
+  }
+
+  let level = objects[PLAYER_IDX].char_attributes.map_or(1, |f| f.level);
+  let xp = objects[PLAYER_IDX].char_attributes.map_or(0, |f| f.xp);
+  render_bar(&mut display.panel, 1, 2, BAR_WIDTH, &format!("Level {} XP", level), xp,
+             xp_to_level_up(level), colors::WHITE, colors::LIGHT_BLUE, colors::DARKER_BLUE);
+
+  display.panel.set_default_foreground(colors::LIGHT_GREY);
+  display.panel.print_ex(1, 3, BackgroundFlag::None, TextAlignment::Left,
+                         format!("Dungeon level: {}", game.depth));
+
+  let player_damage = objects[PLAYER_IDX].char_attributes.map_or(components::Dice { count: 1, sides: 1 },
+                                                                 |f| f.damage);
+  display.panel.print_ex(1, 4, BackgroundFlag::None, TextAlignment::Left,
+                         format!("Damage: {}+{}  Defense: {}", player_damage, objects[PLAYER_IDX].power(game),
+                                 objects[PLAYER_IDX].defense(game)));
+
+  // Equipped gear, wrapped over the last two rows
+  let gear_names: Vec<_> = game.inventory.iter()
+    .filter(|item| item.equipment.map_or(false, |gear| gear.equipped))
+    .map(|item| item.name.clone())
+    .collect();
+  let gear_text = if gear_names.is_empty() {
+    "Gear: none".to_string()
+  } else {
+    format!("Gear: {}", gear_names.join(", "))
+  };
+  display.panel.print_rect(1, 5, BAR_WIDTH, 2, gear_text);
+
   // Objects under player or mouse
-  let mut visible_objects = visible_objects_at_pos(engine.mouse.cx as i32,
-                                                   engine.mouse.cy as i32,
-                                                   objects,
-                                                   &engine.fov);
+  let mut visible_objects = match camera.to_map(engine.mouse.cx as i32, engine.mouse.cy as i32) {
+    Some((x, y)) => visible_objects_at_pos(x, y, objects, fov),
+    None => vec![]
+  };
   if visible_objects.is_empty() {
     visible_objects = visible_objects_at_pos(objects[PLAYER_IDX].x, objects[PLAYER_IDX].y,
-                                             objects, &engine.fov);
+                                             objects, fov);
   }
   let obj_names = visible_objects
                   .iter()
@@ -842,24 +2212,24 @@ fn render_all(game: &mut GameState, engine: &mut EngineState, objects: &[Object]
                   .collect::<Vec<_>>()
                   .join(", ");
 
-  engine.panel.set_default_foreground(colors::LIGHT_GREY);
-  engine.panel.print_ex(1, 0, BackgroundFlag::None, TextAlignment::Left, obj_names);
+  display.panel.set_default_foreground(colors::LIGHT_GREY);
+  display.panel.print_ex(1, 0, BackgroundFlag::None, TextAlignment::Left, obj_names);
 
   // Game messages
   let mut y = MSG_HEIGHT as i32;
   for &(ref msg, color) in game.log.iter().rev() {
-    let msg_height = engine.panel.get_height_rect(MSG_X, y, MSG_WIDTH, 0, msg);
+    let msg_height = display.panel.get_height_rect(MSG_X, y, MSG_WIDTH, 0, msg);
     y -= msg_height;
     if y < 0 {
       break;
     }
-    engine.panel.set_default_foreground(color);
-    engine.panel.print_rect(MSG_X, y, MSG_WIDTH, 0, msg);
+    display.panel.set_default_foreground(color);
+    display.panel.print_rect(MSG_X, y, MSG_WIDTH, 0, msg);
   }
 
-  blit(&engine.panel,
+  blit(&display.panel,
        (0, 0), (SCREEN_WIDTH, PANEL_HEIGHT),
-       &mut engine.root,
+       &mut display.root,
        (0, PANEL_Y), 1.0, 1.0);
 }
 
@@ -874,78 +2244,254 @@ fn initialize_fov(game: &GameState, engine: &mut EngineState) {
 }
 
 
-fn main() {
-  let root = Root::initializer()
-    .font("data/fonts/arial10x10.png", FontLayout::Tcod)
-    .font_type(FontType::Greyscale)
-    .size(SCREEN_WIDTH, SCREEN_HEIGHT)
-    .title("Rusty Roguelike")
-    .init();
-  tcod::system::set_fps(LIMIT_FPS);
+fn exit_with_error(message: &str) -> ! {
+  eprintln!("Error: {}", message);
+  process::exit(1);
+}
+
+/* Headless runs have no panel to show the log in, so new messages are echoed to stdout */
+fn print_new_messages(log: &Messages, num_printed: &mut usize) {
+  for &(ref msg, _) in &log[*num_printed..] {
+    println!("[log] {}", msg);
+  }
+  *num_printed = log.len();
+}
 
+/* Closes out one step of the game loop: the recorder writes out the inputs read during the
+ * step and a replay checks that the resulting state matches the recording.
+ */
+fn end_step(engine: &mut EngineState, replay: &mut Option<replay::Replay>, game: &GameState,
+            objects: &[Object]) {
+  if let Some(ref mut recorder) = engine.recorder {
+    if recorder.has_pending_inputs() {
+      if let Err(err) = recorder.end_step(save::state_checksum(game, objects)) {
+        exit_with_error(&err);
+      }
+    }
+  }
+  if let Some(ref mut replay) = *replay {
+    if let Err(err) = replay.verify_step(save::state_checksum(game, objects)) {
+      exit_with_error(&err);
+    }
+  }
+}
+
+/* A fresh level 1 player, placed by `make_map` */
+fn new_player() -> Object {
+  let mut player = Object::new(0, 0, '@', 'X', "Player Bob", colors::WHITE, true, true);
+  player.alive = true;
+  player.is_player = true;
+  player.char_attributes = Some(components::CharacterAttributes{
+    max_hp: 30, hp: 30, defense: 3, power: 5, damage: components::Dice { count: 1, sides: 4 },
+    evasion: 10, level: 1, xp: 0, speed: PLAYER_SPEED, energy: ACTION_ENERGY, on_hit: None
+  });
+  player
+}
+
+fn main() {
   // Setup the number generator
   let mut thread_ctx: ThreadContext;
 
-  let mut provided_rng_seed: Option<i32> = None;
+  let mut provided_rng_seed: Option<u64> = None;
+  let mut key_script_path: Option<String> = None;
+  let mut load_path: Option<String> = None;
+  let mut record_path: Option<String> = None;
+  let mut replay_path: Option<String> = None;
+  let mut mapgen: Option<mapgen::MapGenKind> = None;
   let mut found_seed_flag = false;
   let mut found_debug_flag = false;
+  let mut found_keys_flag = false;
+  let mut found_load_flag = false;
+  let mut found_record_flag = false;
+  let mut found_replay_flag = false;
+  let mut found_mapgen_flag = false;
   let mut debug_mode = false;
   let mut debug_disable_fog = false;
+  let mut headless = false;
 
   for argument in env::args() {
     if found_seed_flag {
-      provided_rng_seed = Some(argument.trim().parse().expect("seed flag must be a number"));
+      provided_rng_seed = Some(argument.trim().parse().expect("seed flag must be a non-negative integer"));
       found_seed_flag = false;
     } else if found_debug_flag {
       debug_mode = (argument.trim() != "false");
       found_debug_flag = false;
+    } else if found_keys_flag {
+      key_script_path = Some(argument.trim().to_string());
+      found_keys_flag = false;
+    } else if found_load_flag {
+      load_path = Some(argument.trim().to_string());
+      found_load_flag = false;
+    } else if found_record_flag {
+      record_path = Some(argument.trim().to_string());
+      found_record_flag = false;
+    } else if found_replay_flag {
+      replay_path = Some(argument.trim().to_string());
+      found_replay_flag = false;
+    } else if found_mapgen_flag {
+      mapgen = match argument.trim() {
+        "random" => None,
+        name => match mapgen::MapGenKind::parse(name) {
+          Some(kind) => Some(kind),
+          None => {
+            let names: Vec<_> = mapgen::MAP_GENERATORS.iter().map(|&(name, _)| name).collect();
+            exit_with_error(&format!("unknown map generator '{}' (expected random, {})",
+                                     name, names.join(", ")))
+          }
+        }
+      };
+      found_mapgen_flag = false;
     }
     else {
       match argument.as_ref() {
         "--seed"        => found_seed_flag = true,
         "--debug"       => found_debug_flag = true,
         "--disable-fog" => debug_disable_fog = true,
+        "--headless"    => headless = true,
+        "--keys"        => found_keys_flag = true,
+        "--load"        => found_load_flag = true,
+        "--record"      => found_record_flag = true,
+        "--replay"      => found_replay_flag = true,
+        "--mapgen"      => found_mapgen_flag = true,
         _ => {}
       };
     }
   }
 
-  thread_ctx = if provided_rng_seed.is_some() {
-    ThreadContext::from_seed(provided_rng_seed.unwrap())
+  if load_path.is_some() && (record_path.is_some() || replay_path.is_some()) {
+    exit_with_error("--record and --replay start a new game and can't be used with --load");
+  }
+
+  // A replay feeds the recorded keys back in and restarts from the recorded seed. A headless
+  // run without a key file reads its keys from stdin.
+  let mut replay: Option<replay::Replay> = None;
+  let key_script = if let Some(ref path) = replay_path {
+    let (loaded_replay, recorded_keys) = match replay::Replay::load(path) {
+      Ok(loaded) => loaded,
+      Err(err) => exit_with_error(&err)
+    };
+    provided_rng_seed = Some(loaded_replay.seed);
+    mapgen = loaded_replay.mapgen;
+    replay = Some(loaded_replay);
+    Some(Ok(recorded_keys))
+  } else {
+    match (key_script_path, headless) {
+      (Some(path), _) => Some(keys::KeyScript::from_file(&path)),
+      (None, true) => Some(keys::KeyScript::from_stdin()),
+      (None, false) => None
+    }
+  };
+  let input_source = match key_script {
+    Some(Ok(script)) => InputSource::Script(script),
+    Some(Err(err)) => exit_with_error(&err),
+    None => InputSource::Window
+  };
+
+  let display = if headless {
+    None
   } else {
-    ThreadContext::new()
+    let root = Root::initializer()
+      .font("data/fonts/arial10x10.png", FontLayout::Tcod)
+      .font_type(FontType::Greyscale)
+      .size(SCREEN_WIDTH, SCREEN_HEIGHT)
+      .title("Rusty Roguelike")
+      .init();
+    tcod::system::set_fps(LIMIT_FPS);
+
+    Some(Display {
+      root: root,
+      con: Offscreen::new(MAP_WIDTH, MAP_HEIGHT),
+      panel: Offscreen::new(SCREEN_WIDTH, PANEL_HEIGHT),
+      planned_path: vec![]
+    })
+  };
+
+  let content = match Content::load(content::CONTENT_PATH) {
+    Ok(content) => content,
+    Err(err) => exit_with_error(&err)
+  };
+  if let (Some(replay), Some(path)) = (replay.as_ref(), replay_path.as_ref()) {
+    if let Err(err) = replay.check_content(content.checksum) {
+      exit_with_error(&format!("{}: {}", path, err));
+    }
+  }
+
+  // A replay presses keys under the bindings it was recorded with, and a key script under the
+  // defaults, so that neither changes meaning with the local keymap file
+  let keymap = match (replay.as_ref(), &input_source) {
+    (Some(replay), _) => replay.keymap.clone(),
+    (None, &InputSource::Script(_)) => Keymap::defaults(),
+    (None, &InputSource::Window) => match Keymap::load(keymap::KEYMAP_PATH) {
+      Ok(keymap) => keymap,
+      Err(err) => exit_with_error(&err)
+    }
   };
 
   let mut engine = EngineState {
-    root: root,
-    con: Offscreen::new(MAP_WIDTH, MAP_HEIGHT),
-    panel: Offscreen::new(SCREEN_WIDTH, PANEL_HEIGHT),
+    display: display,
+    input: input_source,
+    recorder: None,
     fov: FovMap::new(MAP_WIDTH, MAP_HEIGHT),
     mouse: Default::default(),
+    keymap: keymap
   };
 
-  let mut player = Object::new(0, 0, '@', 'X', "Player Bob", colors::WHITE, true, true);
-  player.alive = true;
-  player.char_attributes = Some(components::CharacterAttributes{
-    max_hp: 30, hp: 30, defense: 3, power: 7
-  });
-
-  let mut objects = vec![player];
-
   let mut game = GameState {
     debug_mode: debug_mode,
     debug_disable_fog: debug_disable_fog,
     log: vec![],
     game_running: true,
     inventory: vec![],
-    map: make_map(&mut thread_ctx, &mut objects)
+    map: vec![],
+    depth: 1,
+    mapgen: mapgen,
+    content: content
   };
+  let mut objects: Vec<Object>;
+
+  // Saving writes back to the file the game was loaded from
+  let save_path = load_path.clone().unwrap_or(save::DEFAULT_SAVE_PATH.into());
+  // Every file holding this game, all of which go once the player dies
+  let mut save_files: Vec<String> = load_path.iter().cloned().collect();
+
+  if let Some(ref path) = load_path {
+    let save_data = match save::load_game(path) {
+      Ok(save_data) => save_data,
+      Err(err) => exit_with_error(&err)
+    };
+    println!("Loaded game from {}", path);
+    thread_ctx = save_data.thread_ctx;
+    objects = save_data.objects;
+    game.map = save_data.map;
+    game.log = save_data.log;
+    game.inventory = save_data.inventory;
+    game.depth = save_data.depth;
+    game.mapgen = save_data.mapgen;
+  } else {
+    thread_ctx = if provided_rng_seed.is_some() {
+      ThreadContext::from_seed(provided_rng_seed.unwrap())
+    } else {
+      ThreadContext::new()
+    };
+
+    objects = vec![new_player()];
+    game.map = make_map(&game.content, game.depth, game.mapgen, &mut thread_ctx, &mut objects);
+  }
+
+  if let Some(ref path) = record_path {
+    match replay::Recorder::create(path, thread_ctx.rand_seed, game.mapgen, game.content.checksum,
+                                 &engine.keymap) {
+      Ok(recorder) => engine.recorder = Some(recorder),
+      Err(err) => exit_with_error(&err)
+    }
+  }
 
   // Init fov
   initialize_fov(&game, &mut engine);
 
-  let mut keypress = Default::default();
   let mut previous_player_pos = (-1, -1);
+  let mut num_turns = 0;
+  let mut num_messages_printed = 0;
 
   while game.game_running {
     let recompute_fov = previous_player_pos != (objects[PLAYER_IDX].x, objects[PLAYER_IDX].y);
@@ -955,11 +2501,14 @@ fn main() {
                              FOV_LIGHT_WALLS, FOV_ALGO);
     }
 
-    match input::check_for_event(input::MOUSE | input::KEY_PRESS) {
-      Some((_, Event::Mouse(m))) => engine.mouse = m,
-      Some((_, Event::Key(k))) => keypress = k,
-      _ => keypress = Default::default(),
-    }
+    // A scripted input source ends the game once it runs out of inputs
+    let input = match engine.poll_input() {
+      Some(input) => input,
+      None => {
+        game.game_running = false;
+        break;
+      }
+    };
 
     // @idea allow the player to do things after death?
     // @idea copy the approach that Dwarf Fortress takes for world gen. Make a world and
@@ -969,46 +2518,125 @@ fn main() {
     //   to visit the body and take scraps if anything is still there.
 
     previous_player_pos = objects[PLAYER_IDX].pos();
-    let player_action = handle_input(keypress, &mut game, &mut engine, &mut objects);
+    let player_action = match input {
+      Input::Key(key) => handle_input(key, &mut game, &mut engine, &mut thread_ctx, &mut objects),
+      Input::Click { x, y, right: false } => {
+        handle_click(x, y, &mut game, &mut engine, &mut thread_ctx, &mut objects)
+      }
+      Input::Click { right: true, .. } => PlayerAction::DidntTakeTurn
+    };
+
+    if player_action == PlayerAction::SaveGame && engine.is_scripted() {
+      game.log.add("Saving is disabled while a script or replay is playing.", colors::LIGHT_GREY);
+    } else if player_action == PlayerAction::SaveGame {
+      match save::save_game(&save_path, &game, &objects, &thread_ctx) {
+        Ok(()) => {
+          game.log.add(format!("Game saved to {}.", save_path), colors::LIGHT_GREY);
+          save_files.push(save_path.clone());
+        }
+        Err(err) => game.log.add(format!("Save failed: {}", err), colors::RED)
+      }
+    }
 
-    if player_action == PlayerAction::Exit || engine.root.window_closed() {
+    if player_action == PlayerAction::Exit || engine.is_window_closed() {
+      end_step(&mut engine, &mut replay, &game, &objects);
+
+      // Don't lose a run just because the window was closed
+      if !engine.is_scripted() && objects[PLAYER_IDX].alive {
+        match save::save_game(save::AUTOSAVE_PATH, &game, &objects, &thread_ctx) {
+          Ok(()) => {
+            println!("Game saved to {}", save::AUTOSAVE_PATH);
+            save_files.push(save::AUTOSAVE_PATH.into());
+          }
+          Err(err) => eprintln!("Error: {}", err)
+        }
+      }
       game.game_running = false;
       break;
     }
 
     // Update monsters
     if game.game_running && player_action == PlayerAction::TookTurn {
-      for id in 0..objects.len() {
-        if objects[id].brain.is_some() && objects[id].alive {
-          ai_take_turn(&mut game, &mut engine, id, &mut objects);
-        }
+      num_turns += 1;
+      run_until_player_ready(&mut game, &mut thread_ctx, &mut objects);
+      level_up(&mut game, &mut engine, &mut objects);
+    }
+
+    let mut map_changed = recompute_fov;
+    if let PlayerAction::TookTurns(num_taken) = player_action {
+      num_turns += num_taken;
+      map_changed = true;
+    }
+    if player_action == PlayerAction::Descend {
+      next_level(&mut game, &mut engine, &mut thread_ctx, &mut objects);
+      map_changed = true;
+    }
+
+    update_map(&mut game, &mut engine.fov, map_changed);
+    end_step(&mut engine, &mut replay, &game, &objects);
+
+    if engine.is_headless() {
+      print_new_messages(&game.log, &mut num_messages_printed);
+      if !objects[PLAYER_IDX].alive {
+        game.game_running = false;
+        break;
       }
+      continue;
     }
 
-    update_map(&mut game, &mut engine.fov, recompute_fov);
+    render_all(&mut game, &mut engine, &objects, map_changed);
+
+    if let Some(ref mut display) = engine.display {
+      if game.debug_mode {
+        let mut seed_type_label = "Active";
+        if thread_ctx.custom_seed {
+          display.root.set_default_foreground(colors::RED);
+          seed_type_label = "Custom";
+        }
+        else {
+          display.root.set_default_foreground(colors::WHITE);
+        }
+        display.root.print_ex(1, SCREEN_HEIGHT - 2, BackgroundFlag::None, TextAlignment::Left,
+                              format!("{} Seed: {}", seed_type_label, thread_ctx.rand_seed));
+      }
 
-    // @improvement create a smooth scrolling camera
-    render_all(&mut game, &mut engine, &objects, recompute_fov);
+      display.root.flush();
+      display.root.clear(); // clears text
 
-    if game.debug_mode {
-      let mut seed_type_label = "Active";
-      if thread_ctx.custom_seed {
-        engine.root.set_default_foreground(colors::RED);
-        seed_type_label = "Custom";
+      // Erase objects at their old locations before moving
+      for object in &objects {
+        object.clear(&mut display.con);
       }
-      else {
-        engine.root.set_default_foreground(colors::WHITE);
+    }
+  }
+
+  // A dead character can't be loaded again
+  if !engine.is_scripted() && !objects[PLAYER_IDX].alive {
+    save_files.sort();
+    save_files.dedup();
+    for path in &save_files {
+      match save::delete_save(path) {
+        Ok(()) => println!("Deleted save file {}", path),
+        Err(err) => eprintln!("Error: {}", err)
       }
-      engine.root.print_ex(1, SCREEN_HEIGHT - 2, BackgroundFlag::None, TextAlignment::Left,
-                           format!("{} Seed: {}", seed_type_label, thread_ctx.rand_seed));
     }
+  }
 
-    engine.root.flush();
-    engine.root.clear(); // clears text
+  if let Some(ref replay) = replay {
+    println!("[replay] Finished. {} steps matched the recording.", replay.num_verified());
+  }
 
-    // Erase objects at their old locations before moving
-    for object in &objects {
-      object.clear(&mut engine.con);
-    }
+  if engine.is_headless() {
+    print_new_messages(&game.log, &mut num_messages_printed);
+    let player = &objects[PLAYER_IDX];
+    let hp = player.char_attributes.map_or(0, |f| f.hp);
+    let monsters_alive = objects.iter().filter(|obj| obj.brain.is_some() && obj.alive).count();
+    // Auto-exploring a level and comparing these shows whether all of it can be reached
+    let num_floor_tiles = game.map.iter().filter(|tile| tile.passable).count();
+    let num_explored = game.map.iter().filter(|tile| tile.passable && tile.explored).count();
+    println!("[headless] Finished after {} turns. Player alive: {}, HP: {}, depth: {}, \
+              position: {:?}, monsters alive: {}, items held: {}, floor explored: {}/{}",
+             num_turns, player.alive, hp, game.depth, player.pos(), monsters_alive,
+             game.inventory.len(), num_explored, num_floor_tiles);
   }
 }
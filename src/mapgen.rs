@@ -0,0 +1,431 @@
+/* Level layouts. Each generator carves a map out of solid wall and reports the rooms (or, for
+ * the ones without rooms, patches of open ground) that `place_objects` fills with monsters and
+ * items, plus where the player and the stairs go.
+ *
+ * Generators only draw from `thread_ctx`, so a seed always produces the same levels.
+ */
+
+use std::cmp;
+use rand::Rng;
+
+use super::{Map, Rect, ThreadContext, Tile, MAP_WIDTH, MAP_HEIGHT};
+
+const ROOM_MAX_SIZE: i32 = 12;
+const ROOM_MIN_SIZE: i32 = 5;
+const MAX_ROOMS: i32 = 30; // Attempts; rooms that would overlap another are skipped
+
+// Levels with fewer rooms (or regions) than this that the player can reach are thrown away
+const MIN_ROOMS: usize = 4;
+
+// BSP keeps splitting areas until they're no bigger than this, then puts a room in each one
+const BSP_MAX_LEAF_SIZE: i32 = 34;
+const BSP_MIN_LEAF_SIZE: i32 = ROOM_MIN_SIZE + 2;
+
+const CAVE_WALL_PERCENT: i32 = 45; // Of the initial noise
+const CAVE_SMOOTHING_PASSES: i32 = 5;
+const CAVE_WALL_NEIGHBORS: i32 = 5; // Out of the 9 tiles around and including a tile
+
+const DRUNKARD_FLOOR_PERCENT: i32 = 35; // Walks stop once this much of the map is dug out
+const DRUNKARD_WALK_LENGTH: i32 = 200;
+
+// Caves and drunkard's walks are split into square regions for spawning. Regions that are
+// mostly wall get nothing.
+const REGION_SIZE: i32 = 20;
+const REGION_MIN_FLOOR_PERCENT: i32 = 25;
+
+pub struct Layout {
+  pub map: Map,
+  pub rooms: Vec<Rect>, // Rooms or regions of open ground, in the order they were made
+  pub player_start: (i32, i32),
+  pub stairs: (i32, i32)
+}
+
+pub trait MapGenerator {
+  fn generate(&self, thread_ctx: &mut ThreadContext) -> Layout;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MapGenKind {
+  Rooms,
+  Bsp,
+  Caves,
+  Drunkard
+}
+
+// Names for --mapgen
+pub const MAP_GENERATORS: &'static [(&'static str, MapGenKind)] = &[
+  ("rooms", MapGenKind::Rooms),
+  ("bsp", MapGenKind::Bsp),
+  ("caves", MapGenKind::Caves),
+  ("drunkard", MapGenKind::Drunkard),
+];
+
+impl MapGenKind {
+  pub fn parse(name: &str) -> Option<Self> {
+    MAP_GENERATORS.iter().find(|&&(gen_name, _)| gen_name == name).map(|&(_, kind)| kind)
+  }
+
+  pub fn name(&self) -> &'static str {
+    MAP_GENERATORS.iter().find(|&&(_, kind)| kind == *self).unwrap().0
+  }
+
+  pub fn random(thread_ctx: &mut ThreadContext) -> Self {
+    let idx = thread_ctx.rand.gen_range(0, MAP_GENERATORS.len() as u32) as usize;
+    MAP_GENERATORS[idx].1
+  }
+
+  pub fn generate(&self, thread_ctx: &mut ThreadContext) -> Layout {
+    match *self {
+      MapGenKind::Rooms => RoomsAndCorridors.generate(thread_ctx),
+      MapGenKind::Bsp => Bsp.generate(thread_ctx),
+      MapGenKind::Caves => Caves.generate(thread_ctx),
+      MapGenKind::Drunkard => DrunkardsWalk.generate(thread_ctx)
+    }
+  }
+}
+
+/* Random rooms that don't overlap, each joined to the one before it by an L-shaped tunnel */
+pub struct RoomsAndCorridors;
+
+impl MapGenerator for RoomsAndCorridors {
+  fn generate(&self, thread_ctx: &mut ThreadContext) -> Layout {
+    let mut map = vec![Tile::wall(); (MAP_WIDTH * MAP_HEIGHT) as usize];
+    let mut rooms = vec![];
+
+    // @improvement Create a sparse tile map.
+
+    for _ in 0..MAX_ROOMS {
+      let w = thread_ctx.rand.gen_range(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
+      let h = thread_ctx.rand.gen_range(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
+      let x = thread_ctx.rand.gen_range(0, MAP_WIDTH - w);
+      let y = thread_ctx.rand.gen_range(0, MAP_HEIGHT - h);
+
+      let room = Rect::new(x, y, w, h);
+      let can_place = !rooms.iter().any(|other_room| room.intersects_with(other_room));
+
+      if can_place {
+        create_room(room, &mut map);
+        if let Some(prev_room) = rooms.last() {
+          connect_rooms(prev_room, &room, thread_ctx, &mut map);
+        }
+        rooms.push(room);
+      }
+    }
+
+    rooms_layout(map, rooms)
+  }
+}
+
+/* Splits the map in two over and over (binary space partitioning) and puts a room in each of
+ * the resulting areas. Rooms end up spread evenly over the map, and since neighbouring areas
+ * are joined first, corridors stay short.
+ */
+pub struct Bsp;
+
+impl MapGenerator for Bsp {
+  fn generate(&self, thread_ctx: &mut ThreadContext) -> Layout {
+    let mut map = vec![Tile::wall(); (MAP_WIDTH * MAP_HEIGHT) as usize];
+
+    // Like the rooms inside them, areas keep their outer row and column as wall
+    let mut leaves = vec![];
+    split_area(Rect::new(0, 0, MAP_WIDTH - 1, MAP_HEIGHT - 1), thread_ctx, &mut leaves);
+
+    let mut rooms: Vec<Rect> = vec![];
+    for leaf in leaves {
+      let leaf_w = leaf.x2 - leaf.x1;
+      let leaf_h = leaf.y2 - leaf.y1;
+      let w = thread_ctx.rand.gen_range(ROOM_MIN_SIZE, cmp::min(ROOM_MAX_SIZE, leaf_w) + 1);
+      let h = thread_ctx.rand.gen_range(ROOM_MIN_SIZE, cmp::min(ROOM_MAX_SIZE, leaf_h) + 1);
+      let x = thread_ctx.rand.gen_range(leaf.x1, leaf.x2 - w + 1);
+      let y = thread_ctx.rand.gen_range(leaf.y1, leaf.y2 - h + 1);
+
+      let room = Rect::new(x, y, w, h);
+      create_room(room, &mut map);
+      if let Some(prev_room) = rooms.last() {
+        connect_rooms(prev_room, &room, thread_ctx, &mut map);
+      }
+      rooms.push(room);
+    }
+
+    rooms_layout(map, rooms)
+  }
+}
+
+/* Adds the areas `area` splits into to `leaves`, depth first so that areas next to each other
+ * end up next to each other in the list.
+ */
+fn split_area(area: Rect, thread_ctx: &mut ThreadContext, leaves: &mut Vec<Rect>) {
+  let w = area.x2 - area.x1;
+  let h = area.y2 - area.y1;
+  let can_split_w = w >= BSP_MIN_LEAF_SIZE * 2;
+  let can_split_h = h >= BSP_MIN_LEAF_SIZE * 2;
+  if (w <= BSP_MAX_LEAF_SIZE && h <= BSP_MAX_LEAF_SIZE) || (!can_split_w && !can_split_h) {
+    leaves.push(area);
+    return;
+  }
+
+  // Cut across the longer side so areas don't get long and thin
+  let (first, second) = if can_split_w && (w >= h || !can_split_h) {
+    let split_w = thread_ctx.rand.gen_range(BSP_MIN_LEAF_SIZE, w - BSP_MIN_LEAF_SIZE + 1);
+    (Rect::new(area.x1, area.y1, split_w, h), Rect::new(area.x1 + split_w, area.y1, w - split_w, h))
+  } else {
+    let split_h = thread_ctx.rand.gen_range(BSP_MIN_LEAF_SIZE, h - BSP_MIN_LEAF_SIZE + 1);
+    (Rect::new(area.x1, area.y1, w, split_h), Rect::new(area.x1, area.y1 + split_h, w, h - split_h))
+  };
+  split_area(first, thread_ctx, leaves);
+  split_area(second, thread_ctx, leaves);
+}
+
+/* Caverns grown from random noise with a cellular automaton: every pass, a tile becomes wall
+ * when most of the tiles around it are wall and floor otherwise. Pockets cut off from the
+ * biggest cavern are filled in.
+ */
+pub struct Caves;
+
+impl MapGenerator for Caves {
+  fn generate(&self, thread_ctx: &mut ThreadContext) -> Layout {
+    let is_border = |x: i32, y: i32| x == 0 || y == 0 || x == MAP_WIDTH - 1 || y == MAP_HEIGHT - 1;
+
+    let mut walls = vec![true; (MAP_WIDTH * MAP_HEIGHT) as usize];
+    for y in 0..MAP_HEIGHT {
+      for x in 0..MAP_WIDTH {
+        let roll = thread_ctx.rand.gen_range(0, 100);
+        walls[(y * MAP_WIDTH + x) as usize] = is_border(x, y) || roll < CAVE_WALL_PERCENT;
+      }
+    }
+
+    for _ in 0..CAVE_SMOOTHING_PASSES {
+      let mut next_walls = walls.clone();
+      for y in 1..(MAP_HEIGHT - 1) {
+        for x in 1..(MAP_WIDTH - 1) {
+          let mut num_walls = 0;
+          for ny in (y - 1)..(y + 2) {
+            for nx in (x - 1)..(x + 2) {
+              if walls[(ny * MAP_WIDTH + nx) as usize] {
+                num_walls += 1;
+              }
+            }
+          }
+          next_walls[(y * MAP_WIDTH + x) as usize] = num_walls >= CAVE_WALL_NEIGHBORS;
+        }
+      }
+      walls = next_walls;
+    }
+
+    let mut map: Map = walls.iter().map(|&wall| if wall { Tile::wall() } else { Tile::empty() }).collect();
+    keep_largest_cavern(&mut map);
+    open_ground_layout(map, thread_ctx)
+  }
+}
+
+/* Digs out the map with random walks, each starting from somewhere already dug, until enough
+ * of it is open. Everything dug is connected by construction.
+ */
+pub struct DrunkardsWalk;
+
+impl MapGenerator for DrunkardsWalk {
+  fn generate(&self, thread_ctx: &mut ThreadContext) -> Layout {
+    const STEPS: [(i32, i32); 4] = [(0, -1), (1, 0), (0, 1), (-1, 0)];
+
+    let mut map = vec![Tile::wall(); (MAP_WIDTH * MAP_HEIGHT) as usize];
+    let target_floor = (MAP_WIDTH * MAP_HEIGHT * DRUNKARD_FLOOR_PERCENT / 100) as usize;
+
+    let mut dug = vec![(MAP_WIDTH / 2, MAP_HEIGHT / 2)];
+    Tile::make_empty(&mut map[((MAP_HEIGHT / 2) * MAP_WIDTH + MAP_WIDTH / 2) as usize]);
+    while dug.len() < target_floor {
+      let (mut x, mut y) = dug[thread_ctx.rand.gen_range(0, dug.len() as u32) as usize];
+      for _ in 0..DRUNKARD_WALK_LENGTH {
+        let (dx, dy) = STEPS[thread_ctx.rand.gen_range(0, STEPS.len() as u32) as usize];
+        // Never dig out the edge of the map
+        x = cmp::max(1, cmp::min(MAP_WIDTH - 2, x + dx));
+        y = cmp::max(1, cmp::min(MAP_HEIGHT - 2, y + dy));
+        let tile = &mut map[(y * MAP_WIDTH + x) as usize];
+        if !tile.passable {
+          Tile::make_empty(tile);
+          dug.push((x, y));
+        }
+      }
+    }
+
+    open_ground_layout(map, thread_ctx)
+  }
+}
+
+/* Places a rect of empty tiles into `map` */
+fn create_room(room: Rect, map: &mut Map) {
+  for y in (room.y1 + 1)..room.y2 {
+    for x in (room.x1 + 1)..room.x2 {
+      map[(y * MAP_WIDTH + x) as usize] = Tile::empty();
+    }
+  }
+}
+
+fn create_h_tunnel(x1: i32, x2: i32, y: i32, map: &mut Map) {
+  for x in cmp::min(x1, x2)..(cmp::max(x1, x2) + 1) {
+    Tile::make_empty(&mut map[(y * MAP_WIDTH + x) as usize]);
+  }
+}
+
+fn create_v_tunnel(y1: i32, y2: i32, x: i32, map: &mut Map) {
+  for y in cmp::min(y1, y2)..(cmp::max(y1, y2) + 1) {
+    Tile::make_empty(&mut map[(y * MAP_WIDTH + x) as usize]);
+  }
+}
+
+fn connect_rooms(from: &Rect, to: &Rect, thread_ctx: &mut ThreadContext, map: &mut Map) {
+  let (prev_x, prev_y) = from.center();
+  let (new_x, new_y) = to.center();
+
+  // draw a coin to pick the type of tunnel
+  if thread_ctx.rand.gen::<bool>() {
+    create_h_tunnel(prev_x, new_x, prev_y, map);
+    create_v_tunnel(prev_y, new_y, new_x, map);
+  } else {
+    create_v_tunnel(prev_y, new_y, prev_x, map);
+    create_h_tunnel(prev_x, new_x, new_y, map);
+  }
+}
+
+/* The player starts in the first room and the way down is always in the last one */
+fn rooms_layout(map: Map, rooms: Vec<Rect>) -> Layout {
+  Layout {
+    player_start: rooms[0].center(),
+    stairs: rooms[rooms.len() - 1].center(),
+    map: map,
+    rooms: rooms
+  }
+}
+
+/* For maps without rooms: the player starts on a random open tile, the stairs go on the open
+ * tile furthest from there, and spawns are spread over the regions of the map with enough open
+ * ground.
+ */
+fn open_ground_layout(map: Map, thread_ctx: &mut ThreadContext) -> Layout {
+  let open_tiles: Vec<_> = (0..(MAP_WIDTH * MAP_HEIGHT))
+    .filter(|&idx| map[idx as usize].passable)
+    .map(|idx| (idx % MAP_WIDTH, idx / MAP_WIDTH))
+    .collect();
+  if open_tiles.is_empty() {
+    // Solid rock. `validate` throws it away.
+    return Layout { map: map, rooms: vec![], player_start: (0, 0), stairs: (0, 0) };
+  }
+  let player_start = open_tiles[thread_ctx.rand.gen_range(0, open_tiles.len() as u32) as usize];
+
+  let distances = walking_distances(&map, player_start);
+  let stairs = open_tiles.iter()
+    .cloned()
+    .max_by_key(|&(x, y)| distances[(y * MAP_WIDTH + x) as usize])
+    .unwrap();
+
+  let mut regions = vec![];
+  for region_y in 0..((MAP_HEIGHT + REGION_SIZE - 1) / REGION_SIZE) {
+    for region_x in 0..((MAP_WIDTH + REGION_SIZE - 1) / REGION_SIZE) {
+      let (x1, y1) = (region_x * REGION_SIZE, region_y * REGION_SIZE);
+      let (x2, y2) = (cmp::min(MAP_WIDTH, x1 + REGION_SIZE), cmp::min(MAP_HEIGHT, y1 + REGION_SIZE));
+      let num_open = open_tiles.iter()
+        .filter(|&&(x, y)| x >= x1 && x < x2 && y >= y1 && y < y2)
+        .count() as i32;
+      if num_open * 100 >= (x2 - x1) * (y2 - y1) * REGION_MIN_FLOOR_PERCENT {
+        // Rooms count their outer row and column as wall, so grow the region by one to match
+        regions.push(Rect::new(x1 - 1, y1 - 1, x2 - x1 + 1, y2 - y1 + 1));
+      }
+    }
+  }
+
+  Layout { map: map, rooms: regions, player_start: player_start, stairs: stairs }
+}
+
+/* Checks that a level is playable, fixing what can be fixed: floor the player can't walk to
+ * from their start is filled in, and rooms left with no floor are dropped. Returns why the
+ * level has to be thrown away otherwise.
+ */
+pub fn validate(layout: &mut Layout) -> Result<(), String> {
+  let (start_x, start_y) = layout.player_start;
+  if !layout.map[(start_y * MAP_WIDTH + start_x) as usize].passable {
+    return Err("the player starts inside a wall".into());
+  }
+
+  let distances = walking_distances(&layout.map, layout.player_start);
+  let is_reachable = |x: i32, y: i32| distances[(y * MAP_WIDTH + x) as usize].is_some();
+
+  let (stairs_x, stairs_y) = layout.stairs;
+  if !is_reachable(stairs_x, stairs_y) {
+    return Err("the stairs can't be reached".into());
+  }
+  if layout.stairs == layout.player_start {
+    return Err("the stairs are where the player starts".into());
+  }
+
+  for (idx, tile) in layout.map.iter_mut().enumerate() {
+    if tile.passable && distances[idx].is_none() {
+      *tile = Tile::wall();
+    }
+  }
+  layout.rooms.retain(|room| {
+    ((room.y1 + 1)..room.y2).any(|y| ((room.x1 + 1)..room.x2).any(|x| is_reachable(x, y)))
+  });
+  if layout.rooms.len() < MIN_ROOMS {
+    return Err(format!("only {} rooms can be reached, needs at least {}", layout.rooms.len(), MIN_ROOMS));
+  }
+
+  Ok(())
+}
+
+/* Fills in every open tile that can't be walked to from the biggest open area */
+fn keep_largest_cavern(map: &mut Map) {
+  let mut cavern_ids = vec![usize::max_value(); map.len()];
+  let mut cavern_sizes = vec![];
+  for idx in 0..map.len() {
+    if !map[idx].passable || cavern_ids[idx] != usize::max_value() {
+      continue;
+    }
+    let start = (idx as i32 % MAP_WIDTH, idx as i32 / MAP_WIDTH);
+    let distances = walking_distances(map, start);
+    let mut size = 0;
+    for (tile_idx, distance) in distances.iter().enumerate() {
+      if distance.is_some() {
+        cavern_ids[tile_idx] = cavern_sizes.len();
+        size += 1;
+      }
+    }
+    cavern_sizes.push(size);
+  }
+
+  let largest = (0..cavern_sizes.len()).max_by_key(|&id| (cavern_sizes[id], cmp::Reverse(id)));
+  for idx in 0..map.len() {
+    if map[idx].passable && Some(cavern_ids[idx]) != largest {
+      map[idx] = Tile::wall();
+    }
+  }
+}
+
+/* Steps needed to walk from `start` to every tile, moving in eight directions. None for the
+ * tiles that can't be reached.
+ */
+pub fn walking_distances(map: &Map, start: (i32, i32)) -> Vec<Option<i32>> {
+  let mut distances = vec![None; map.len()];
+  let mut frontier = vec![start];
+  distances[(start.1 * MAP_WIDTH + start.0) as usize] = Some(0);
+  let mut distance = 0;
+  while !frontier.is_empty() {
+    distance += 1;
+    let mut next_frontier = vec![];
+    for (x, y) in frontier {
+      for dy in -1..2 {
+        for dx in -1..2 {
+          let (nx, ny) = (x + dx, y + dy);
+          if nx < 0 || nx >= MAP_WIDTH || ny < 0 || ny >= MAP_HEIGHT {
+            continue;
+          }
+          let idx = (ny * MAP_WIDTH + nx) as usize;
+          if map[idx].passable && distances[idx].is_none() {
+            distances[idx] = Some(distance);
+            next_frontier.push((nx, ny));
+          }
+        }
+      }
+    }
+    frontier = next_frontier;
+  }
+  distances
+}
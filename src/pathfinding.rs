@@ -0,0 +1,145 @@
+/* A* search over a grid of tiles, moving in eight directions.
+ *
+ * Costs are integers (10 for a straight step, 14 for a diagonal one) so that the same map
+ * always produces the same path, which replays and save files depend on.
+ */
+
+use std::cmp::{self, Ordering};
+use std::collections::BinaryHeap;
+
+const STRAIGHT_COST: u32 = 10;
+const DIAGONAL_COST: u32 = 14;
+
+// Searched in this order, which decides between paths of equal cost
+const NEIGHBORS: [(i32, i32); 8] = [
+  (0, -1), (1, 0), (0, 1), (-1, 0),
+  (1, -1), (1, 1), (-1, 1), (-1, -1)
+];
+
+#[derive(PartialEq, Eq)]
+struct Node {
+  estimate: u32, // Cost so far plus the heuristic
+  cost: u32,
+  idx: usize
+}
+
+// Reversed so that BinaryHeap, a max-heap, pops the cheapest node first
+impl Ord for Node {
+  fn cmp(&self, other: &Node) -> Ordering {
+    other.estimate.cmp(&self.estimate)
+      .then_with(|| self.cost.cmp(&other.cost))
+      .then_with(|| other.idx.cmp(&self.idx))
+  }
+}
+
+impl PartialOrd for Node {
+  fn partial_cmp(&self, other: &Node) -> Option<Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+/* Finds the cheapest path from `start` to `goal` on a `width` x `height` grid.
+ *
+ * `tile_cost` returns the extra cost of stepping onto a tile, or None when it can't be
+ * entered at all. The goal is always treated as enterable since it's usually occupied by
+ * whatever is being chased. The returned steps are in walking order and don't include
+ * `start`. Returns None when the goal can't be reached.
+ */
+pub fn find_path<F>(width: i32, height: i32, start: (i32, i32), goal: (i32, i32),
+                    tile_cost: F) -> Option<Vec<(i32, i32)>>
+  where F: Fn(i32, i32) -> Option<u32>
+{
+  if goal.0 < 0 || goal.0 >= width || goal.1 < 0 || goal.1 >= height {
+    return None;
+  }
+  search(width, height, start, |x, y| (x, y) == goal, |pos| heuristic(pos, goal), tile_cost)
+}
+
+/* Like `find_path`, but to whichever tile for which `is_goal` returns true is cheapest to reach.
+ * Goal tiles are always treated as enterable.
+ */
+pub fn find_path_to_nearest<G, F>(width: i32, height: i32, start: (i32, i32), is_goal: G,
+                                  tile_cost: F) -> Option<Vec<(i32, i32)>>
+  where G: Fn(i32, i32) -> bool, F: Fn(i32, i32) -> Option<u32>
+{
+  // No heuristic can know where the nearest goal is, which makes this Dijkstra's algorithm
+  search(width, height, start, is_goal, |_| 0, tile_cost)
+}
+
+fn search<G, H, F>(width: i32, height: i32, start: (i32, i32), is_goal: G, heuristic: H,
+                   tile_cost: F) -> Option<Vec<(i32, i32)>>
+  where G: Fn(i32, i32) -> bool, H: Fn((i32, i32)) -> u32, F: Fn(i32, i32) -> Option<u32>
+{
+  let in_bounds = |x: i32, y: i32| x >= 0 && x < width && y >= 0 && y < height;
+  if !in_bounds(start.0, start.1) {
+    return None;
+  }
+  if is_goal(start.0, start.1) {
+    return Some(vec![]);
+  }
+
+  let to_idx = |x: i32, y: i32| (y * width + x) as usize;
+  let num_tiles = (width * height) as usize;
+  let start_idx = to_idx(start.0, start.1);
+
+  let mut best_cost = vec![u32::max_value(); num_tiles];
+  let mut came_from = vec![usize::max_value(); num_tiles];
+  let mut open = BinaryHeap::new();
+
+  best_cost[start_idx] = 0;
+  open.push(Node { estimate: heuristic(start), cost: 0, idx: start_idx });
+
+  while let Some(node) = open.pop() {
+    let (x, y) = (node.idx as i32 % width, node.idx as i32 / width);
+    if is_goal(x, y) {
+      let mut path = vec![];
+      let mut idx = node.idx;
+      while idx != start_idx {
+        path.push((idx as i32 % width, idx as i32 / width));
+        idx = came_from[idx];
+      }
+      path.reverse();
+      return Some(path);
+    }
+    if node.cost > best_cost[node.idx] {
+      continue; // A cheaper way here was already expanded
+    }
+
+    for &(dx, dy) in NEIGHBORS.iter() {
+      let (next_x, next_y) = (x + dx, y + dy);
+      if !in_bounds(next_x, next_y) {
+        continue;
+      }
+      let extra_cost = if is_goal(next_x, next_y) {
+        0
+      } else {
+        match tile_cost(next_x, next_y) {
+          Some(extra_cost) => extra_cost,
+          None => continue
+        }
+      };
+
+      let step_cost = if dx != 0 && dy != 0 { DIAGONAL_COST } else { STRAIGHT_COST };
+      let cost = node.cost + step_cost + extra_cost;
+      let next_idx = to_idx(next_x, next_y);
+      if cost < best_cost[next_idx] {
+        best_cost[next_idx] = cost;
+        came_from[next_idx] = node.idx;
+        open.push(Node {
+          estimate: cost + heuristic((next_x, next_y)),
+          cost: cost,
+          idx: next_idx
+        });
+      }
+    }
+  }
+
+  None
+}
+
+/* Octile distance: the cost of the shortest path with nothing in the way */
+fn heuristic((x1, y1): (i32, i32), (x2, y2): (i32, i32)) -> u32 {
+  let dx = (x1 - x2).abs() as u32;
+  let dy = (y1 - y2).abs() as u32;
+  STRAIGHT_COST * cmp::max(dx, dy) + (DIAGONAL_COST - STRAIGHT_COST) * cmp::min(dx, dy)
+}
@@ -0,0 +1,210 @@
+/* Recording and playback of play sessions.
+ *
+ * A recording is a header line, the seed the game was started with, the map generator it was
+ * started with (`random` when none was given to --mapgen), a checksum of the content file it
+ * was played with, the key bindings in use (one line per action, see
+ * `keymap::Keymap::action_keys`), and then one line per step of the game loop:
+ *
+ *   RUSTY-ROGUELIKE-REPLAY <version>
+ *   seed <seed>
+ *   mapgen <generator>
+ *   content <content checksum>
+ *   bind <action> [<key> ...]
+ *   <state checksum> <input> [<input> ...]
+ *
+ * Inputs are written the same way as in a key script (see `keys::parse_input`). The first
+ * input on a step line is the one handed to `handle_input`; any inputs after it were read by
+ * menus or targeting opened during that step. The checksum is `save::state_checksum` taken at the
+ * end of the step, so a replay can tell exactly where it stopped matching the recording.
+ */
+
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use content;
+use keymap::Keymap;
+use keys::{self, Input, KeyScript};
+use mapgen::MapGenKind;
+
+const REPLAY_MAGIC: &'static str = "RUSTY-ROGUELIKE-REPLAY";
+const REPLAY_VERSION: u32 = 6;
+
+pub struct Recorder {
+  out: BufWriter<File>,
+  path: String,
+  step_inputs: Vec<String>
+}
+
+impl Recorder {
+  pub fn create(path: &str, seed: u64, mapgen: Option<MapGenKind>, content_checksum: u64,
+                keymap: &Keymap) -> Result<Self, String> {
+    let file = File::create(path)
+      .map_err(|err| format!("unable to create recording {}: {}", path, err))?;
+    let mut recorder = Recorder {
+      out: BufWriter::new(file),
+      path: path.into(),
+      step_inputs: vec![]
+    };
+    let mapgen_name = mapgen.map_or("random", |kind| kind.name());
+    let mut header = format!("{} {}\nseed {}\nmapgen {}\ncontent {:016x}\n",
+                             REPLAY_MAGIC, REPLAY_VERSION, seed, mapgen_name, content_checksum);
+    for (action, key_names) in keymap.action_keys() {
+      header.push_str(&format!("bind {}", action));
+      for key_name in key_names {
+        header.push_str(&format!(" {}", key_name));
+      }
+      header.push('\n');
+    }
+    recorder.write(&header)?;
+    Ok(recorder)
+  }
+
+  /* Inputs that have no script name (e.g. an empty key from an idle frame) are ignored since
+   * they never change the game state.
+   */
+  pub fn record_input(&mut self, input: &Input) {
+    if let Some(name) = keys::input_name(input) {
+      self.step_inputs.push(name);
+    }
+  }
+
+  pub fn has_pending_inputs(&self) -> bool {
+    !self.step_inputs.is_empty()
+  }
+
+  pub fn end_step(&mut self, state_checksum: u64) -> Result<(), String> {
+    let line = format!("{:016x} {}\n", state_checksum, self.step_inputs.join(" "));
+    self.step_inputs.clear();
+    self.write(&line)
+  }
+
+  /* Flushed on every step so a crash still leaves a usable recording behind */
+  fn write(&mut self, text: &str) -> Result<(), String> {
+    let out = &mut self.out;
+    out.write_all(text.as_bytes())
+      .and_then(|_| out.flush())
+      .map_err(|err| format!("unable to write recording {}: {}", self.path, err))
+  }
+}
+
+pub struct Replay {
+  pub seed: u64,
+  pub mapgen: Option<MapGenKind>,
+  pub keymap: Keymap,
+  content_checksum: u64,
+  checksums: Vec<u64>,
+  num_verified: usize
+}
+
+impl Replay {
+  /* Returns the replay along with a script of every recorded input, in order */
+  pub fn load(path: &str) -> Result<(Self, KeyScript), String> {
+    let mut text = String::new();
+    File::open(path)
+      .and_then(|mut file| file.read_to_string(&mut text))
+      .map_err(|err| format!("unable to read recording {}: {}", path, err))?;
+    Replay::parse(&text).map_err(|err| format!("{}: {}", path, err))
+  }
+
+  fn parse(text: &str) -> Result<(Self, KeyScript), String> {
+    let mut lines = text.lines();
+
+    let header = lines.next().unwrap_or("");
+    let header_parts: Vec<&str> = header.split_whitespace().collect();
+    if header_parts.len() != 2 || header_parts[0] != REPLAY_MAGIC {
+      return Err("not a recording (bad header)".into());
+    }
+    if header_parts[1] != REPLAY_VERSION.to_string() {
+      return Err(format!("recording version {} is not supported (expected version {})",
+                         header_parts[1], REPLAY_VERSION));
+    }
+
+    let mut seed_parts = lines.next().unwrap_or("").split_whitespace();
+    let seed = match (seed_parts.next(), seed_parts.next()) {
+      (Some("seed"), Some(value)) => {
+        value.parse().map_err(|_| format!("line 2: invalid seed '{}'", value))?
+      }
+      _ => return Err("line 2: expected the seed".into())
+    };
+
+    let mut mapgen_parts = lines.next().unwrap_or("").split_whitespace();
+    let mapgen = match (mapgen_parts.next(), mapgen_parts.next()) {
+      (Some("mapgen"), Some("random")) => None,
+      (Some("mapgen"), Some(name)) => {
+        Some(MapGenKind::parse(name).ok_or_else(|| format!("line 3: unknown map generator '{}'", name))?)
+      }
+      _ => return Err("line 3: expected the map generator".into())
+    };
+
+    let mut content_parts = lines.next().unwrap_or("").split_whitespace();
+    let content_checksum = match (content_parts.next(), content_parts.next()) {
+      (Some("content"), Some(value)) => u64::from_str_radix(value, 16)
+        .map_err(|_| format!("line 4: invalid content checksum '{}'", value))?,
+      _ => return Err("line 4: expected the content checksum".into())
+    };
+
+    let mut lines = lines.peekable();
+    let mut action_keys = vec![];
+    while lines.peek().map_or(false, |line| line.starts_with("bind ")) {
+      let line_num = action_keys.len() + 5;
+      let mut parts = lines.next().unwrap().split_whitespace().skip(1);
+      let action = parts.next().ok_or_else(|| format!("line {}: expected an action", line_num))?;
+      action_keys.push((action.to_string(), parts.map(|name| name.to_string()).collect(), line_num));
+    }
+    let keymap = Keymap::from_action_keys(&action_keys)?;
+
+    let mut checksums = vec![];
+    let mut recorded_inputs = vec![];
+    for (idx, line) in lines.enumerate() {
+      let line_num = idx + action_keys.len() + 5;
+      let mut parts = line.split_whitespace();
+      let checksum = match parts.next() {
+        Some(checksum) => u64::from_str_radix(checksum, 16)
+          .map_err(|_| format!("line {}: invalid checksum '{}'", line_num, checksum))?,
+        None => continue
+      };
+      let mut num_inputs = 0;
+      for name in parts {
+        let input = keys::parse_input(name)
+          .ok_or_else(|| format!("line {}: unknown input '{}'", line_num, name))?;
+        recorded_inputs.push(input);
+        num_inputs += 1;
+      }
+      if num_inputs == 0 {
+        return Err(format!("line {}: step has no inputs", line_num));
+      }
+      checksums.push(checksum);
+    }
+
+    let replay = Replay { seed: seed, mapgen: mapgen, keymap: keymap, content_checksum: content_checksum, checksums: checksums, num_verified: 0 };
+    Ok((replay, KeyScript::from_inputs(recorded_inputs)))
+  }
+
+  /* A recording only plays back the same way with the content it was recorded with */
+  pub fn check_content(&self, content_checksum: u64) -> Result<(), String> {
+    if content_checksum == self.content_checksum {
+      Ok(())
+    } else {
+      Err(format!("the recording was made with a different {} (checksum {:016x}, the current one is {:016x})",
+                  content::CONTENT_PATH, self.content_checksum, content_checksum))
+    }
+  }
+
+  pub fn num_verified(&self) -> usize {
+    self.num_verified
+  }
+
+  /* Checks the state at the end of the next step against the recording */
+  pub fn verify_step(&mut self, state_checksum: u64) -> Result<(), String> {
+    let step = self.num_verified + 1;
+    match self.checksums.get(self.num_verified) {
+      Some(&expected) if expected == state_checksum => {
+        self.num_verified += 1;
+        Ok(())
+      }
+      Some(&expected) => Err(format!("replay diverged at step {}: expected state {:016x}, got {:016x}",
+                                     step, expected, state_checksum)),
+      None => Err(format!("replay diverged at step {}: the recording has only {} steps",
+                          step, self.checksums.len()))
+    }
+  }
+}
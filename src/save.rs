@@ -0,0 +1,844 @@
+/* Versioned on-disk save format.
+ *
+ * A save file is a header line followed by a body of whitespace separated tokens:
+ *
+ *   RUSTY-ROGUELIKE-SAVE <version> <checksum>
+ *   <body>
+ *
+ * The checksum is a 64-bit FNV-1a hash of the body, written in hex, and is used to reject
+ * files that were truncated or edited by hand. Strings are written as `<byte length>:<bytes>`
+ * so they can hold whitespace. Any change to the body layout must bump SAVE_VERSION.
+ */
+
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read, Write};
+use tcod::colors::Color;
+
+use components;
+use mapgen::MapGenKind;
+use rng::Pcg32;
+use super::{Object, Tile, Map, Messages, GameState, ThreadContext, MAP_WIDTH, MAP_HEIGHT, PLAYER_IDX};
+
+const SAVE_MAGIC: &'static str = "RUSTY-ROGUELIKE-SAVE";
+pub const SAVE_VERSION: u32 = 1;
+
+pub const DEFAULT_SAVE_PATH: &'static str = "savegame.sav";
+// Written when the game is closed, so it never replaces a save the player made
+pub const AUTOSAVE_PATH: &'static str = "autosave.sav";
+
+/* Everything needed to resume a game. The debug flags in GameState come from the command
+ * line and are not saved.
+ */
+pub struct SaveData {
+  pub map: Map,
+  pub depth: i32,
+  pub mapgen: Option<MapGenKind>,
+  pub log: Messages,
+  pub inventory: Vec<Object>,
+  pub objects: Vec<Object>,
+  pub thread_ctx: ThreadContext
+}
+
+pub fn save_game(path: &str, game: &GameState, objects: &[Object],
+                 thread_ctx: &ThreadContext) -> Result<(), String> {
+  let mut writer = SaveWriter::new();
+
+  writer.tag("rng");
+  thread_ctx.rand_seed.save(&mut writer);
+  thread_ctx.custom_seed.save(&mut writer);
+  thread_ctx.rand.save(&mut writer);
+  writer.newline();
+
+  writer.tag("map");
+  MAP_WIDTH.save(&mut writer);
+  MAP_HEIGHT.save(&mut writer);
+  writer.newline();
+  save_map(&game.map, &mut writer);
+
+  writer.tag("depth");
+  game.depth.save(&mut writer);
+  writer.newline();
+
+  writer.tag("mapgen");
+  game.mapgen.save(&mut writer);
+  writer.newline();
+
+  writer.tag("log");
+  game.log.save(&mut writer);
+  writer.newline();
+
+  writer.tag("inventory");
+  game.inventory.save(&mut writer);
+  writer.newline();
+
+  writer.tag("objects");
+  save_slice(objects, &mut writer);
+  writer.newline();
+
+  writer.tag("end");
+  writer.newline();
+
+  let body = writer.finish();
+  let contents = format!("{} {} {:016x}\n{}", SAVE_MAGIC, SAVE_VERSION, checksum(&body), body);
+  // Written next to the save and moved over it once it's safely on disk, so a crash or a full
+  // disk partway through leaves the old save intact
+  let tmp_path = format!("{}.tmp", path);
+  File::create(&tmp_path)
+    .and_then(|mut file| {
+      file.write_all(contents.as_bytes())?;
+      file.flush()?;
+      file.sync_all()
+    })
+    .and_then(|_| fs::rename(&tmp_path, path))
+    .map_err(|err| {
+      let _ = fs::remove_file(&tmp_path);
+      format!("unable to write save file {}: {}", path, err)
+    })
+}
+
+/* A save that's already gone is fine */
+pub fn delete_save(path: &str) -> Result<(), String> {
+  match fs::remove_file(path) {
+    Err(ref err) if err.kind() != ErrorKind::NotFound => {
+      Err(format!("unable to delete save file {}: {}", path, err))
+    }
+    _ => Ok(())
+  }
+}
+
+pub fn load_game(path: &str) -> Result<SaveData, String> {
+  let mut contents = String::new();
+  File::open(path)
+    .and_then(|mut file| file.read_to_string(&mut contents))
+    .map_err(|err| format!("unable to read save file {}: {}", path, err))?;
+
+  parse_save(&contents).map_err(|err| format!("{}: {}", path, err))
+}
+
+fn parse_save(contents: &str) -> Result<SaveData, String> {
+  let header_end = contents.find('\n').ok_or("not a save file (missing header)")?;
+  let (header, body) = (&contents[..header_end], &contents[header_end + 1..]);
+
+  let header_parts: Vec<&str> = header.split_whitespace().collect();
+  if header_parts.len() != 3 || header_parts[0] != SAVE_MAGIC {
+    return Err("not a save file (bad header)".into());
+  }
+  let version: u32 = header_parts[1].parse()
+    .map_err(|_| format!("invalid save version '{}'", header_parts[1]))?;
+  if version != SAVE_VERSION {
+    return Err(format!("save file version {} is not supported (expected version {})",
+                       version, SAVE_VERSION));
+  }
+  let expected_checksum = u64::from_str_radix(header_parts[2], 16)
+    .map_err(|_| format!("invalid checksum '{}'", header_parts[2]))?;
+  if checksum(body) != expected_checksum {
+    return Err("save file is corrupt (checksum mismatch)".into());
+  }
+
+  let mut reader = SaveReader::new(body);
+
+  reader.expect_tag("rng")?;
+  let rand_seed = u64::load(&mut reader)?;
+  let custom_seed = bool::load(&mut reader)?;
+  let rand = Pcg32::load(&mut reader)?;
+
+  reader.expect_tag("map")?;
+  let width = i32::load(&mut reader)?;
+  let height = i32::load(&mut reader)?;
+  if (width, height) != (MAP_WIDTH, MAP_HEIGHT) {
+    return Err(format!("map size {}x{} does not match the game's map size {}x{}",
+                       width, height, MAP_WIDTH, MAP_HEIGHT));
+  }
+  let map = load_map(&mut reader)?;
+
+  reader.expect_tag("depth")?;
+  let depth = i32::load(&mut reader)?;
+
+  reader.expect_tag("mapgen")?;
+  let mapgen = Option::<MapGenKind>::load(&mut reader)?;
+
+  reader.expect_tag("log")?;
+  let log = Messages::load(&mut reader)?;
+
+  reader.expect_tag("inventory")?;
+  let inventory = Vec::<Object>::load(&mut reader)?;
+
+  reader.expect_tag("objects")?;
+  let objects = Vec::<Object>::load(&mut reader)?;
+  match objects.get(PLAYER_IDX) {
+    Some(player) if player.is_player && player.char_attributes.is_some() => {}
+    _ => return Err("save file has no player object".into())
+  }
+
+  reader.expect_tag("end")?;
+  reader.expect_eof()?;
+
+  Ok(SaveData {
+    map: map,
+    depth: depth,
+    mapgen: mapgen,
+    log: log,
+    inventory: inventory,
+    objects: objects,
+    thread_ctx: ThreadContext::restore(rand_seed, custom_seed, rand)
+  })
+}
+
+/* A hash of everything a save file holds except the RNG. Used by replays to check that a
+ * played back session is still in step with the recording.
+ */
+pub fn state_checksum(game: &GameState, objects: &[Object]) -> u64 {
+  let mut writer = SaveWriter::new();
+  save_map(&game.map, &mut writer);
+  game.depth.save(&mut writer);
+  game.log.save(&mut writer);
+  game.inventory.save(&mut writer);
+  save_slice(objects, &mut writer);
+  checksum(&writer.finish())
+}
+
+/* 64-bit FNV-1a */
+pub fn checksum(text: &str) -> u64 {
+  let mut hash: u64 = 0xcbf29ce484222325;
+  for byte in text.bytes() {
+    hash ^= byte as u64;
+    hash = hash.wrapping_mul(0x100000001b3);
+  }
+  hash
+}
+
+/* Tiles are packed into one hex digit each, one map row per line */
+fn save_map(map: &Map, writer: &mut SaveWriter) {
+  for row in map.chunks(MAP_WIDTH as usize) {
+    let packed: String = row.iter().map(|tile| {
+      let bits = (tile.passable as u32) |
+                 (tile.blocks_sight as u32) << 1 |
+                 (tile.explored as u32) << 2 |
+                 (tile.visible as u32) << 3;
+      ::std::char::from_digit(bits, 16).unwrap()
+    }).collect();
+    writer.token(&packed);
+    writer.newline();
+  }
+}
+
+fn load_map(reader: &mut SaveReader) -> Result<Map, String> {
+  let mut map = Vec::with_capacity((MAP_WIDTH * MAP_HEIGHT) as usize);
+  for _ in 0..MAP_HEIGHT {
+    let row = reader.token("map row")?;
+    if row.len() != MAP_WIDTH as usize {
+      return Err(reader.error(&format!("map row has {} tiles, expected {}", row.len(), MAP_WIDTH)));
+    }
+    for c in row.chars() {
+      let bits = c.to_digit(16).ok_or_else(|| reader.error(&format!("invalid tile '{}'", c)))?;
+      map.push(Tile {
+        passable: bits & 1 != 0,
+        blocks_sight: bits & 2 != 0,
+        explored: bits & 4 != 0,
+        visible: bits & 8 != 0
+      });
+    }
+  }
+  Ok(map)
+}
+
+
+pub struct SaveWriter {
+  out: String,
+  at_line_start: bool
+}
+
+impl SaveWriter {
+  fn new() -> Self {
+    SaveWriter { out: String::new(), at_line_start: true }
+  }
+
+  pub fn token(&mut self, token: &str) {
+    if !self.at_line_start {
+      self.out.push(' ');
+    }
+    self.out.push_str(token);
+    self.at_line_start = false;
+  }
+
+  pub fn tag(&mut self, tag: &str) {
+    self.token(tag);
+  }
+
+  pub fn newline(&mut self) {
+    self.out.push('\n');
+    self.at_line_start = true;
+  }
+
+  fn finish(self) -> String {
+    self.out
+  }
+}
+
+pub struct SaveReader<'a> {
+  text: &'a str,
+  pos: usize
+}
+
+impl<'a> SaveReader<'a> {
+  fn new(text: &'a str) -> Self {
+    SaveReader { text: text, pos: 0 }
+  }
+
+  /* The header takes up the first line of the file, so body lines are offset by one */
+  pub fn error(&self, message: &str) -> String {
+    let line = self.text[..self.pos].matches('\n').count() + 2;
+    format!("line {}: {}", line, message)
+  }
+
+  fn skip_whitespace(&mut self) {
+    let rest = &self.text[self.pos..];
+    self.pos += rest.find(|c: char| !c.is_whitespace()).unwrap_or(rest.len());
+  }
+
+  pub fn token(&mut self, what: &str) -> Result<&'a str, String> {
+    self.skip_whitespace();
+    let rest = &self.text[self.pos..];
+    let len = rest.find(char::is_whitespace).unwrap_or(rest.len());
+    if len == 0 {
+      return Err(self.error(&format!("unexpected end of file, expected {}", what)));
+    }
+    self.pos += len;
+    Ok(&rest[..len])
+  }
+
+  pub fn expect_tag(&mut self, tag: &str) -> Result<(), String> {
+    let start = self.pos;
+    let found = self.token(tag)?;
+    if found != tag {
+      self.pos = start;
+      return Err(self.error(&format!("expected section '{}', found '{}'", tag, found)));
+    }
+    Ok(())
+  }
+
+  pub fn parse<T: ::std::str::FromStr>(&mut self, what: &str) -> Result<T, String> {
+    let token = self.token(what)?;
+    token.parse().map_err(|_| self.error(&format!("invalid {} '{}'", what, token)))
+  }
+
+  pub fn string(&mut self) -> Result<String, String> {
+    self.skip_whitespace();
+    let rest = &self.text[self.pos..];
+    let colon = rest.find(':').ok_or_else(|| self.error("expected a string"))?;
+    let len: usize = rest[..colon].parse()
+      .map_err(|_| self.error(&format!("invalid string length '{}'", &rest[..colon])))?;
+    let start = colon + 1;
+    let end = start + len;
+    if end > rest.len() || !rest.is_char_boundary(end) {
+      return Err(self.error("string runs past the end of the file"));
+    }
+    self.pos += end;
+    Ok(rest[start..end].to_string())
+  }
+
+  fn expect_eof(&mut self) -> Result<(), String> {
+    self.skip_whitespace();
+    if self.pos != self.text.len() {
+      return Err(self.error("unexpected data after the end of the save"));
+    }
+    Ok(())
+  }
+}
+
+
+pub trait Saveable: Sized {
+  fn save(&self, writer: &mut SaveWriter);
+  fn load(reader: &mut SaveReader) -> Result<Self, String>;
+}
+
+macro_rules! saveable_number {
+  ($($t:ty),*) => {
+    $(
+      impl Saveable for $t {
+        fn save(&self, writer: &mut SaveWriter) {
+          writer.token(&self.to_string());
+        }
+
+        fn load(reader: &mut SaveReader) -> Result<Self, String> {
+          reader.parse(stringify!($t))
+        }
+      }
+    )*
+  }
+}
+
+saveable_number!(i32, u32, u64, u8);
+
+impl Saveable for bool {
+  fn save(&self, writer: &mut SaveWriter) {
+    writer.token(if *self { "1" } else { "0" });
+  }
+
+  fn load(reader: &mut SaveReader) -> Result<Self, String> {
+    match reader.token("bool")? {
+      "1" => Ok(true),
+      "0" => Ok(false),
+      other => Err(reader.error(&format!("invalid bool '{}'", other)))
+    }
+  }
+}
+
+impl Saveable for char {
+  fn save(&self, writer: &mut SaveWriter) {
+    (*self as u32).save(writer);
+  }
+
+  fn load(reader: &mut SaveReader) -> Result<Self, String> {
+    let code = u32::load(reader)?;
+    ::std::char::from_u32(code).ok_or_else(|| reader.error(&format!("invalid char {}", code)))
+  }
+}
+
+impl Saveable for String {
+  fn save(&self, writer: &mut SaveWriter) {
+    writer.token(&format!("{}:{}", self.len(), self));
+  }
+
+  fn load(reader: &mut SaveReader) -> Result<Self, String> {
+    reader.string()
+  }
+}
+
+impl Saveable for Color {
+  fn save(&self, writer: &mut SaveWriter) {
+    self.r.save(writer);
+    self.g.save(writer);
+    self.b.save(writer);
+  }
+
+  fn load(reader: &mut SaveReader) -> Result<Self, String> {
+    Ok(Color { r: u8::load(reader)?, g: u8::load(reader)?, b: u8::load(reader)? })
+  }
+}
+
+impl<T: Saveable> Saveable for Option<T> {
+  fn save(&self, writer: &mut SaveWriter) {
+    match *self {
+      Some(ref value) => {
+        writer.token("+");
+        value.save(writer);
+      }
+      None => writer.token("-")
+    }
+  }
+
+  fn load(reader: &mut SaveReader) -> Result<Self, String> {
+    match reader.token("option")? {
+      "+" => Ok(Some(T::load(reader)?)),
+      "-" => Ok(None),
+      other => Err(reader.error(&format!("invalid option marker '{}'", other)))
+    }
+  }
+}
+
+fn save_slice<T: Saveable>(values: &[T], writer: &mut SaveWriter) {
+  (values.len() as u32).save(writer);
+  for value in values {
+    writer.newline();
+    value.save(writer);
+  }
+}
+
+impl<T: Saveable> Saveable for Vec<T> {
+  fn save(&self, writer: &mut SaveWriter) {
+    save_slice(self, writer);
+  }
+
+  fn load(reader: &mut SaveReader) -> Result<Self, String> {
+    let len = u32::load(reader)?;
+    let mut values = Vec::with_capacity(len as usize);
+    for _ in 0..len {
+      values.push(T::load(reader)?);
+    }
+    Ok(values)
+  }
+}
+
+impl<A: Saveable, B: Saveable> Saveable for (A, B) {
+  fn save(&self, writer: &mut SaveWriter) {
+    self.0.save(writer);
+    self.1.save(writer);
+  }
+
+  fn load(reader: &mut SaveReader) -> Result<Self, String> {
+    Ok((A::load(reader)?, B::load(reader)?))
+  }
+}
+
+impl Saveable for Pcg32 {
+  fn save(&self, writer: &mut SaveWriter) {
+    let (state, inc) = self.state();
+    state.save(writer);
+    inc.save(writer);
+  }
+
+  fn load(reader: &mut SaveReader) -> Result<Self, String> {
+    let state = u64::load(reader)?;
+    let inc = u64::load(reader)?;
+    Pcg32::from_state(state, inc).map_err(|err| reader.error(&err))
+  }
+}
+
+impl Saveable for MapGenKind {
+  fn save(&self, writer: &mut SaveWriter) {
+    writer.token(self.name());
+  }
+
+  fn load(reader: &mut SaveReader) -> Result<Self, String> {
+    let name = reader.token("map generator")?;
+    MapGenKind::parse(name).ok_or_else(|| reader.error(&format!("unknown map generator '{}'", name)))
+  }
+}
+
+impl Saveable for components::CharacterAttributes {
+  fn save(&self, writer: &mut SaveWriter) {
+    self.max_hp.save(writer);
+    self.hp.save(writer);
+    self.defense.save(writer);
+    self.power.save(writer);
+    self.damage.save(writer);
+    self.evasion.save(writer);
+    self.level.save(writer);
+    self.xp.save(writer);
+    self.speed.save(writer);
+    self.energy.save(writer);
+    self.on_hit.save(writer);
+  }
+
+  fn load(reader: &mut SaveReader) -> Result<Self, String> {
+    let attributes = components::CharacterAttributes {
+      max_hp: i32::load(reader)?,
+      hp: i32::load(reader)?,
+      defense: i32::load(reader)?,
+      power: i32::load(reader)?,
+      damage: components::Dice::load(reader)?,
+      evasion: i32::load(reader)?,
+      level: i32::load(reader)?,
+      xp: i32::load(reader)?,
+      speed: i32::load(reader)?,
+      energy: i32::load(reader)?,
+      on_hit: Option::load(reader)?
+    };
+    // Nothing would ever act again with no speed
+    if attributes.speed < 1 {
+      return Err(reader.error(&format!("invalid speed {}", attributes.speed)));
+    }
+    Ok(attributes)
+  }
+}
+
+impl Saveable for components::Dice {
+  fn save(&self, writer: &mut SaveWriter) {
+    self.count.save(writer);
+    self.sides.save(writer);
+  }
+
+  fn load(reader: &mut SaveReader) -> Result<Self, String> {
+    let count = i32::load(reader)?;
+    let sides = i32::load(reader)?;
+    components::Dice::new(count, sides)
+      .ok_or_else(|| reader.error(&format!("invalid dice {}d{}", count, sides)))
+  }
+}
+
+impl Saveable for components::AiMode {
+  fn save(&self, writer: &mut SaveWriter) {
+    use components::AiMode::*;
+    let name = match *self {
+      Idle => "idle",
+      Wander => "wander",
+      Hunt => "hunt",
+      Flee => "flee"
+    };
+    writer.token(name);
+  }
+
+  fn load(reader: &mut SaveReader) -> Result<Self, String> {
+    use components::AiMode::*;
+    match reader.token("ai mode")? {
+      "idle" => Ok(Idle),
+      "wander" => Ok(Wander),
+      "hunt" => Ok(Hunt),
+      "flee" => Ok(Flee),
+      other => Err(reader.error(&format!("unknown ai mode '{}'", other)))
+    }
+  }
+}
+
+impl Saveable for components::Ai {
+  fn save(&self, writer: &mut SaveWriter) {
+    self.sight_radius.save(writer);
+    self.mode.save(writer);
+    self.last_seen_player.save(writer);
+    self.chase_turns_left.save(writer);
+  }
+
+  fn load(reader: &mut SaveReader) -> Result<Self, String> {
+    Ok(components::Ai {
+      sight_radius: i32::load(reader)?,
+      mode: components::AiMode::load(reader)?,
+      last_seen_player: Option::load(reader)?,
+      chase_turns_left: i32::load(reader)?
+    })
+  }
+}
+
+impl Saveable for components::StatusKind {
+  fn save(&self, writer: &mut SaveWriter) {
+    use components::StatusKind::*;
+    let name = match *self {
+      Poison => "poison",
+      Regeneration => "regeneration",
+      Confusion => "confusion",
+      Stun => "stun",
+      Haste => "haste"
+    };
+    writer.token(name);
+  }
+
+  fn load(reader: &mut SaveReader) -> Result<Self, String> {
+    use components::StatusKind::*;
+    match reader.token("status")? {
+      "poison" => Ok(Poison),
+      "regeneration" => Ok(Regeneration),
+      "confusion" => Ok(Confusion),
+      "stun" => Ok(Stun),
+      "haste" => Ok(Haste),
+      other => Err(reader.error(&format!("unknown status '{}'", other)))
+    }
+  }
+}
+
+impl Saveable for components::StatusEffect {
+  fn save(&self, writer: &mut SaveWriter) {
+    self.kind.save(writer);
+    self.turns_left.save(writer);
+    self.magnitude.save(writer);
+  }
+
+  fn load(reader: &mut SaveReader) -> Result<Self, String> {
+    Ok(components::StatusEffect {
+      kind: components::StatusKind::load(reader)?,
+      turns_left: i32::load(reader)?,
+      magnitude: i32::load(reader)?
+    })
+  }
+}
+
+impl Saveable for components::Item {
+  fn save(&self, writer: &mut SaveWriter) {
+    use components::Item::*;
+    let name = match *self {
+      Heal => "heal",
+      Lightning => "lightning",
+      Fireball => "fireball",
+      Confuse => "confuse",
+      Teleport => "teleport",
+      Regenerate => "regenerate",
+      Haste => "haste",
+      Poison => "poison"
+    };
+    writer.token(name);
+  }
+
+  fn load(reader: &mut SaveReader) -> Result<Self, String> {
+    use components::Item::*;
+    match reader.token("item")? {
+      "heal" => Ok(Heal),
+      "lightning" => Ok(Lightning),
+      "fireball" => Ok(Fireball),
+      "confuse" => Ok(Confuse),
+      "teleport" => Ok(Teleport),
+      "regenerate" => Ok(Regenerate),
+      "haste" => Ok(Haste),
+      "poison" => Ok(Poison),
+      other => Err(reader.error(&format!("unknown item '{}'", other)))
+    }
+  }
+}
+
+impl Saveable for components::Slot {
+  fn save(&self, writer: &mut SaveWriter) {
+    use components::Slot::*;
+    let name = match *self {
+      MainHand => "main_hand",
+      OffHand => "off_hand",
+      Head => "head",
+      Body => "body"
+    };
+    writer.token(name);
+  }
+
+  fn load(reader: &mut SaveReader) -> Result<Self, String> {
+    use components::Slot::*;
+    match reader.token("slot")? {
+      "main_hand" => Ok(MainHand),
+      "off_hand" => Ok(OffHand),
+      "head" => Ok(Head),
+      "body" => Ok(Body),
+      other => Err(reader.error(&format!("unknown slot '{}'", other)))
+    }
+  }
+}
+
+impl Saveable for components::Equipment {
+  fn save(&self, writer: &mut SaveWriter) {
+    self.slot.save(writer);
+    self.equipped.save(writer);
+    self.power_bonus.save(writer);
+    self.defense_bonus.save(writer);
+  }
+
+  fn load(reader: &mut SaveReader) -> Result<Self, String> {
+    Ok(components::Equipment {
+      slot: components::Slot::load(reader)?,
+      equipped: bool::load(reader)?,
+      power_bonus: i32::load(reader)?,
+      defense_bonus: i32::load(reader)?
+    })
+  }
+}
+
+impl Saveable for Object {
+  fn save(&self, writer: &mut SaveWriter) {
+    self.x.save(writer);
+    self.y.save(writer);
+    self.char.save(writer);
+    self.death_char.save(writer);
+    self.color.save(writer);
+    self.name.save(writer);
+    self.blocks.save(writer);
+    self.alive.save(writer);
+    self.show_when_dead.save(writer);
+    self.always_visible.save(writer);
+    self.is_player.save(writer);
+    self.is_stairs.save(writer);
+    self.path.save(writer);
+    self.char_attributes.save(writer);
+    self.brain.save(writer);
+    self.item.save(writer);
+    self.equipment.save(writer);
+    self.status_effects.save(writer);
+  }
+
+  fn load(reader: &mut SaveReader) -> Result<Self, String> {
+    let object = Object {
+      x: i32::load(reader)?,
+      y: i32::load(reader)?,
+      char: char::load(reader)?,
+      death_char: char::load(reader)?,
+      color: Color::load(reader)?,
+      name: String::load(reader)?,
+      blocks: bool::load(reader)?,
+      alive: bool::load(reader)?,
+      show_when_dead: bool::load(reader)?,
+      always_visible: bool::load(reader)?,
+      is_player: bool::load(reader)?,
+      is_stairs: bool::load(reader)?,
+      path: Vec::load(reader)?,
+      char_attributes: Option::load(reader)?,
+      brain: Option::load(reader)?,
+      item: Option::load(reader)?,
+      equipment: Option::load(reader)?,
+      status_effects: Vec::load(reader)?
+    };
+    // Positions index the map, so they have to be on it
+    let on_map = |&(x, y): &(i32, i32)| x >= 0 && x < MAP_WIDTH && y >= 0 && y < MAP_HEIGHT;
+    let last_seen_player = object.brain.and_then(|brain| brain.last_seen_player);
+    let mut positions = Some(object.pos()).into_iter().chain(last_seen_player).chain(object.path.iter().cloned());
+    if let Some(pos) = positions.find(|pos| !on_map(pos)) {
+      return Err(reader.error(&format!("{} has a position {:?} outside the map", object.name, pos)));
+    }
+    Ok(object)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::env;
+  use std::fs;
+  use tcod::colors;
+
+  use components::{StatusEffect, StatusKind};
+  use content::{self, Content};
+  use mapgen::MapGenKind;
+  use {make_map, new_player, GameState, MessageLog, ThreadContext, PLAYER_IDX};
+  use super::*;
+
+  fn generated_game(thread_ctx: &mut ThreadContext) -> (GameState, Vec<Object>) {
+    let content = Content::load(content::CONTENT_PATH).unwrap();
+    let mut objects = vec![new_player()];
+    let map = make_map(&content, 3, Some(MapGenKind::Caves), thread_ctx, &mut objects);
+    let mut game = GameState {
+      debug_mode: false,
+      debug_disable_fog: false,
+      log: vec![],
+      game_running: true,
+      inventory: vec![],
+      map: map,
+      depth: 3,
+      mapgen: Some(MapGenKind::Caves),
+      content: content
+    };
+    game.log.add("Welcome to the dungeon", colors::RED);
+    game.map[0].explored = true;
+    objects[PLAYER_IDX].status_effects.push(StatusEffect { kind: StatusKind::Poison, turns_left: 4, magnitude: 2 });
+    if let Some(item_id) = objects.iter().position(|obj| obj.item.is_some()) {
+      game.inventory.push(objects.remove(item_id));
+    }
+    (game, objects)
+  }
+
+  fn temp_save_path(name: &str) -> String {
+    env::temp_dir().join(name).to_string_lossy().into_owned()
+  }
+
+  #[test]
+  fn save_and_load_round_trip() {
+    let mut thread_ctx = ThreadContext::from_seed(7);
+    let (game, objects) = generated_game(&mut thread_ctx);
+    assert!(!game.inventory.is_empty());
+
+    let path = temp_save_path("roguelike-round-trip.sav");
+    save_game(&path, &game, &objects, &thread_ctx).unwrap();
+    let loaded = load_game(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(loaded.map, game.map);
+    assert_eq!(loaded.depth, game.depth);
+    assert_eq!(loaded.mapgen, game.mapgen);
+    assert_eq!(loaded.log, game.log);
+    assert_eq!(loaded.inventory, game.inventory);
+    assert_eq!(loaded.objects, objects);
+    assert_eq!(loaded.objects[PLAYER_IDX].status_effects, objects[PLAYER_IDX].status_effects);
+    assert_eq!(loaded.thread_ctx.rand, thread_ctx.rand);
+    assert_eq!(loaded.thread_ctx.rand_seed, thread_ctx.rand_seed);
+    assert_eq!(loaded.thread_ctx.custom_seed, thread_ctx.custom_seed);
+  }
+
+  #[test]
+  fn flipped_byte_fails_the_checksum() {
+    let mut thread_ctx = ThreadContext::from_seed(7);
+    let (game, objects) = generated_game(&mut thread_ctx);
+    let path = temp_save_path("roguelike-flipped-byte.sav");
+    save_game(&path, &game, &objects, &thread_ctx).unwrap();
+    let mut bytes = fs::read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    let body_start = bytes.iter().position(|&byte| byte == b'\n').unwrap() + 1;
+    let idx = (body_start..bytes.len()).find(|&idx| bytes[idx].is_ascii_digit()).unwrap();
+    bytes[idx] = if bytes[idx] == b'0' { b'1' } else { b'0' };
+    let contents = String::from_utf8(bytes).unwrap();
+
+    match parse_save(&contents) {
+      Err(err) => assert!(err.contains("checksum mismatch"), "unexpected error: {}", err),
+      Ok(_) => panic!("a corrupt save loaded")
+    }
+  }
+}